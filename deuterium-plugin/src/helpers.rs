@@ -35,3 +35,15 @@ pub fn generate_macro_invocation(cx: &mut base::ExtCtxt, macro_name: &str, macro
         span: sp
     })
 }
+
+/// FNV-1a hash of `source` as a hex string. Used to fingerprint migration files.
+pub fn checksum(source: &str) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+
+    for byte in source.bytes() {
+        hash = hash ^ (byte as u64);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    format!("{:016x}", hash)
+}
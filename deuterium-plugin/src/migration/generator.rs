@@ -3,14 +3,27 @@ use syntax::ext::base;
 use syntax::ext::build::AstBuilder;
 use syntax::util::small_vector;
 use std::ascii::AsciiExt;
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path;
 
 use super::super::helpers;
 
+/// Contents of a plain SQL migration, from either an `.up.sql`/`.down.sql`
+/// pair or a single `.sql` file with `-- up` and `-- down` sections.
+#[derive(Default)]
+struct SqlSource {
+    up: Option<String>,
+    down: Option<String>,
+    baseline: bool
+}
+
 impl super::super::Generator<()> for super::MigrationState {
     fn generate<'a>(self, sp: codemap::Span, cx: &mut base::ExtCtxt, _: ()) -> Box<base::MacResult + 'a> {
 
         let pathes = ::std::fs::read_dir(&self.path).unwrap();
         let mut migrations = vec![];
+        let mut sql_migrations: BTreeMap<(u64, String), SqlSource> = BTreeMap::new();
 
         let path_checker = regex!(r"^_(\d{12})");
         let upcaser = regex!(r"_([a-z])");
@@ -22,6 +35,7 @@ impl super::super::Generator<()> for super::MigrationState {
                 Some(f) => f.to_str().unwrap(),
                 None => { continue }
             };
+            let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
             let captures = path_checker.captures(&filestem[..]);
 
             if captures.is_none() { continue };
@@ -31,14 +45,66 @@ impl super::super::Generator<()> for super::MigrationState {
             let version: u64 = tm.parse().ok().expect("Timestamp must be valid u64");
             let name = filestem.replace(captures.at(0).unwrap(), "");
 
-            let name = upcaser.replace_all(&name, |caps: &::regex::Captures| {
-                caps.at(1).unwrap().to_ascii_uppercase()
-            });
+            let source = read_source(&path);
+
+            // `_<timestamp>_baseline*` migrations stand in for every older version.
+            // A newer baseline can squash an older one; the runner uses the newest.
+            let is_baseline = match extension {
+                "rs" | "sql" => name == "_baseline" || name.starts_with("_baseline_") || name.starts_with("_baseline."),
+                _ => false
+            };
+
+            match extension {
+                "rs" => {
+                    let name = upcaser.replace_all(&name, |caps: &::regex::Captures| {
+                        caps.at(1).unwrap().to_ascii_uppercase()
+                    });
 
-            migrations.push(format!("({}, {}, {})", filestem.to_string(), version, name.to_string()));
+                    migrations.push(format!("({}, {}, {}, {:?}, {})", filestem.to_string(), version, name.to_string(),
+                        helpers::checksum(&source), is_baseline));
+                },
+                "sql" => {
+                    let (name, section) = if name.ends_with(".up") {
+                        (name[..name.len() - 3].to_string(), Some("up"))
+                    } else if name.ends_with(".down") {
+                        (name[..name.len() - 5].to_string(), Some("down"))
+                    } else {
+                        (name.clone(), None)
+                    };
+
+                    let name = upcaser.replace_all(&name, |caps: &::regex::Captures| {
+                        caps.at(1).unwrap().to_ascii_uppercase()
+                    });
+
+                    let sql = sql_migrations.entry((version, name)).or_insert(SqlSource::default());
+                    sql.baseline = is_baseline;
+                    match section {
+                        Some("up") => sql.up = Some(source),
+                        Some(_) => sql.down = Some(source),
+                        None => {
+                            let (up, down) = split_sections(&source);
+                            sql.up = Some(up);
+                            sql.down = down;
+                        }
+                    }
+                },
+                _ => continue
+            }
         }
 
-        let macro_body = migrations.connect(", ");
+        let sql_migrations: Vec<String> = sql_migrations.into_iter().map(|((version, name), sql)| {
+            let up = sql.up.expect(&format!("Please provide up SQL for migration {}", version));
+            let down = match sql.down {
+                Some(ref down) => format!("Some({:?})", down),
+                None => "None".to_string()
+            };
+            let checksum = helpers::checksum(&(up.clone() + sql.down.as_ref().map_or("", |d| &d[..])));
+
+            format!("({}, {}, {:?}, {:?}, {}, {})", version, name, checksum, up, down, sql.baseline)
+        }).collect();
+
+        let connection = self.connection.clone().unwrap_or("::postgres::Connection".to_string());
+        let macro_body = format!("{}; {}; {}", connection, migrations.connect(", "), sql_migrations.connect(", "));
 
         let mut impls = vec![];
         impls.push(helpers::generate_macro_invocation(cx, "migrations", macro_body, sp));
@@ -46,3 +112,32 @@ impl super::super::Generator<()> for super::MigrationState {
 
     }
 }
+
+fn read_source(path: &path::Path) -> String {
+    let mut source = String::new();
+    ::std::fs::File::open(path).and_then(|mut f| f.read_to_string(&mut source))
+        .ok().expect("Can't read migration file");
+    source
+}
+
+/// Splits a single-file SQL migration on its `-- up` and `-- down` marker
+/// lines. Without an `-- up` marker everything before `-- down` is `up`.
+fn split_sections(source: &str) -> (String, Option<String>) {
+    let mut up = vec![];
+    let mut down: Option<Vec<&str>> = None;
+
+    for line in source.lines() {
+        match &line.trim().to_ascii_lowercase()[..] {
+            "-- up" => continue,
+            "-- down" => { down = Some(vec![]); continue },
+            _ => ()
+        }
+
+        match down {
+            Some(ref mut down) => down.push(line),
+            None => up.push(line)
+        }
+    }
+
+    (up.connect("\n"), down.map(|down| down.connect("\n")))
+}
@@ -1,9 +1,11 @@
 #[macro_export]
 macro_rules! migrations {
-    ($(($m:ident, $ver: expr, $name:ident)),*) => (
+    ($conn:ty;
+     $(($m:ident, $ver: expr, $name:ident, $checksum:expr, $baseline:expr)),*;
+     $(($sql_ver:expr, $sql_name:ident, $sql_checksum:expr, $up:expr, $down:expr, $sql_baseline:expr)),*) => (
 
         // FIMXE Move this to `deuterium-orm` after it will work there.
-        pub fn migrations<'a>() -> ::deuterium_orm::migration::Migrations {
+        pub fn migrations<'a>() -> ::deuterium_orm::migration::Migrations<$conn> {
             let mut migrations = Vec::new();
 
             $(
@@ -13,12 +15,25 @@ macro_rules! migrations {
                 let migration = ::deuterium_orm::migration::Migration::new(
                     $ver,
                     name,
-                    Box::new($m::$name) as Box<::deuterium_orm::migration::RawMigration<::postgres::Connection>>
-                );
+                    Box::new($m::$name) as Box<::deuterium_orm::migration::RawMigration<$conn>>
+                ).with_checksum($checksum).baseline($baseline);
 
                 migrations.push(Box::new(migration));
             )*
 
+            $(
+                let name = stringify!($sql_name);
+                let migration = ::deuterium_orm::migration::Migration::new(
+                    $sql_ver,
+                    name,
+                    Box::new(::deuterium_orm::migration::SqlMigration::new($up, $down))
+                        as Box<::deuterium_orm::migration::RawMigration<$conn>>
+                ).with_checksum($sql_checksum).baseline($sql_baseline);
+
+                migrations.push(Box::new(migration));
+            )*
+
+            migrations.sort_by(|a, b| a.version().cmp(b.version()));
             migrations
         }
 
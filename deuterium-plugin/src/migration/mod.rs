@@ -10,7 +10,8 @@ mod generator;
 
 #[derive(Clone)]
 pub struct MigrationState {
-    pub path: path::PathBuf
+    pub path: path::PathBuf,
+    pub connection: Option<String>
 }
 
 pub fn migration<'cx>(cx: &'cx mut base::ExtCtxt, sp: codemap::Span, tokens: &[ast::TokenTree]) -> Box<base::MacResult + 'cx> {
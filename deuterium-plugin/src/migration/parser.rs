@@ -1,6 +1,7 @@
 use syntax::{ast, codemap};
 use syntax::ext::base;
-use syntax::parse::parser;
+use syntax::parse::{parser, token};
+use syntax::print::pprust;
 
 impl<'a, 'b> super::super::Parser<(codemap::Span, &'a mut base::ExtCtxt<'b>)> for super::MigrationState {
     fn parse(parser: &mut parser::Parser,
@@ -19,8 +20,16 @@ impl<'a, 'b> super::super::Parser<(codemap::Span, &'a mut base::ExtCtxt<'b>)> fo
             }
         };
 
+        // Optional connection type the migrations are written against.
+        let connection = if parser.eat(&token::Comma) {
+            Some(pprust::ty_to_string(&*parser.parse_ty()))
+        } else {
+            None
+        };
+
         super::MigrationState{
-            path: ::std::env::current_dir().map(|dir| dir.join(&::std::path::Path::new(&path.to_string()[..]))).unwrap()
+            path: ::std::env::current_dir().map(|dir| dir.join(&::std::path::Path::new(&path.to_string()[..]))).unwrap(),
+            connection: connection
         }
     }
 }
@@ -39,6 +39,7 @@ impl super::super::Generator<()> for super::ModelState {
                 field_ty,
                 format!("{}_f", field_name),
                 format!("get_{}", field_name),
+                format!("try_get_{}", field_name),
                 format!("set_{}", field_name),
                 format!("__{}_changed", field_name),
                 format!("{}_changed", field_name),
@@ -54,7 +55,7 @@ impl super::super::Generator<()> for super::ModelState {
             ts_name.clone() + "OneSelectQueryExt",
             name.name.as_str(),
             format!("[{}]", ts_fields.iter().map(|s| {
-                let a: &[&str] = &[&s.0, &s.1, &s.2, &s.3, &s.4, &s.5, &s.6, &s.7];
+                let a: &[&str] = &[&s.0, &s.1, &s.2, &s.3, &s.4, &s.5, &s.6, &s.7, &s.8];
                 format!("({})", a.connect(", "))
             }).collect::<Vec<String>>().connect(", ")),
             format!("[{}]", self.before_create.connect(", ")),
@@ -71,9 +72,11 @@ impl super::super::Generator<()> for super::ModelState {
             Some(ref primary_key) if primary_key.is_empty() => panic!("Please provide primary key for {}", struct_name),
             Some(ref primary_key) => {
                 let lookup_predicate = generate_lookup_predicate(&struct_name, primary_key);
-                let impl_primary_key_mac = helpers::generate_macro_invocation(cx, "primary_key", format!("self, {}, {}",
+                let loaded = generate_primary_key_loaded(primary_key);
+                let impl_primary_key_mac = helpers::generate_macro_invocation(cx, "primary_key", format!("self, {}, {}, {}",
                     struct_name,
-                    lookup_predicate
+                    lookup_predicate,
+                    loaded
                 ), sp);
 
                 impls.push(impl_primary_key_mac);
@@ -94,3 +97,8 @@ fn generate_lookup_predicate(struct_name: &String, primary_key: &Vec<String>) ->
     }).collect();
     format!("{{{}}}", keys.connect(".and"))
 }
+
+fn generate_primary_key_loaded(primary_key: &Vec<String>) -> String {
+    let keys: Vec<String> = primary_key.iter().map(|pk| format!("self.{}.is_some()", pk)).collect();
+    format!("{{{}}}", keys.connect(" && "))
+}
@@ -18,6 +18,7 @@ macro_rules! define_model {
             $field_type:ty, // Field type, e.g. Uuid
             $field_name_f:ident, // Own field getter name, e.g. `id_f`
             $field_get:ident,  // Field's value getter name
+            $field_try_get:ident,  // Fallible getter name, e.g. `try_get_id`
             $field_set:ident,  // Field's value setter name
             $field_changed_flag:ident, // the name of internal flag field
             $field_changed_accessor:ident, // accessor name
@@ -79,6 +80,14 @@ macro_rules! define_model {
                     return self.$field_name.as_ref().unwrap();
                 }
 
+                #[allow(dead_code)]
+                pub fn $field_try_get(&self) -> ::std::result::Result<&$field_type, ::deuterium_orm::Error> {
+                    self.$field_name.as_ref().ok_or(::deuterium_orm::Error::UnloadedField {
+                        table: $table_name,
+                        field: stringify!($field_name)
+                    })
+                }
+
                 #[allow(dead_code)]
                 pub fn $field_set(&mut self, value: $field_type) {
                     self.$field_name = Some(value);
@@ -131,6 +140,28 @@ macro_rules! define_model {
                     }
                 }
             }
+
+            fn try_from_row<T, L>(query: &::deuterium::SelectQuery<T, L, $model>, row: &::postgres::Row)
+                    -> ::std::result::Result<$model, ::deuterium_orm::Error> {
+                match query.get_select() {
+                    &::deuterium::Select::All => {
+                        Ok($model {
+                           $(
+                                $field_name: Some(try!(row.get_opt(stringify!($field_name)).map_err(|e| {
+                                    ::deuterium_orm::Error::Decode {
+                                        column: stringify!($field_name).to_string(),
+                                        expected: stringify!($field_type),
+                                        error: e
+                                    }
+                                }))),
+                           )+
+                           __meta: $model_meta::new()
+                        })
+                    },
+                    // Columns missing from a partial select stay unloaded.
+                    &::deuterium::Select::Only(_) => Ok(::deuterium_orm::adapter::postgres::FromRow::from_row(query, row))
+                }
+            }
         }
 
         // We also generate ModelTable struct to deal with requests.
@@ -247,6 +278,27 @@ macro_rules! define_model {
                 $model::table().delete().where_(self.lookup_predicate())
             }
 
+            // Fallible versions of `update_query` and `delete_query` for models whose
+            // primary key was never set or loaded.
+
+            pub fn try_update_query(&mut self)
+                    -> ::std::result::Result<::deuterium::UpdateQuery<(), ::deuterium::NoResult, $model>, ::deuterium_orm::Error> {
+                if !self.has_primary_key() {
+                    return Err(::deuterium_orm::Error::MissingPrimaryKey { table: $table_name });
+                }
+
+                Ok(self.update_query())
+            }
+
+            pub fn try_delete_query(&mut self)
+                    -> ::std::result::Result<::deuterium::DeleteQuery<(), ::deuterium::NoResult, $model>, ::deuterium_orm::Error> {
+                if !self.has_primary_key() {
+                    return Err(::deuterium_orm::Error::MissingPrimaryKey { table: $table_name });
+                }
+
+                Ok(self.delete_query())
+            }
+
         }
 
         impl ::deuterium::Table for $table {
@@ -294,12 +346,18 @@ macro_rules! define_model {
 
 #[macro_export]
 macro_rules! primary_key {
-    ($s:ident, $model:ident, $body:block) => (
+    ($s:ident, $model:ident, $body:block, $loaded:block) => (
         impl $model {
             #[allow(dead_code)]
             pub fn lookup_predicate(&$s) -> ::deuterium::SharedPredicate {
                 $body
             }
+
+            /// `true` when every primary key field has a value.
+            #[allow(dead_code)]
+            pub fn has_primary_key(&$s) -> bool {
+                $loaded
+            }
         }
         // TODO get_primary()
         // TODO get_primary_f()
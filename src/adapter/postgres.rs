@@ -1,12 +1,15 @@
 use postgres::{
     GenericConnection,
-    Statement
+    Statement,
+    Transaction
 };
 
 use postgres::rows::Rows;
 use postgres::Result as PostgresResult;
 use postgres::types::ToSql;
 use deuterium::{SqlContext, QueryToSql};
+use std::cmp;
+use std::thread;
 
 pub type PostgresPool = ::r2d2::Pool<::r2d2_postgres::PostgresConnectionManager>;
 pub type PostgresPooledConnection<'a> = ::r2d2::PooledConnection<
@@ -14,14 +17,14 @@ pub type PostgresPooledConnection<'a> = ::r2d2::PooledConnection<
     ::r2d2_postgres::PostgresConnectionManager,
 >;
 
-pub fn setup(cn_str: &str, pool_size: u32) -> PostgresPool {
-    let manager = ::r2d2_postgres::PostgresConnectionManager::new(cn_str, ::postgres::SslMode::None).unwrap();
+pub fn setup(cn_str: &str, pool_size: u32) -> ::error::Result<PostgresPool> {
+    let manager = try!(::r2d2_postgres::PostgresConnectionManager::new(cn_str, ::postgres::SslMode::None));
     let config = ::r2d2::Config::builder()
         .pool_size(pool_size)
         .build();
 
     let handler = Box::new(::r2d2::NoopErrorHandler);
-    ::r2d2::Pool::new(config, manager, handler).unwrap()
+    Ok(try!(::r2d2::Pool::new(config, manager, handler)))
 }
 
 #[allow(missing_copy_implementations)]
@@ -62,14 +65,156 @@ impl PostgresAdapter {
     }
 }
 
-pub trait FromRow {
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IsolationLevel {
+    ReadCommitted,
+    RepeatableRead,
+    Serializable
+}
+
+impl IsolationLevel {
+    fn to_sql(&self) -> &'static str {
+        match *self {
+            IsolationLevel::ReadCommitted => "READ COMMITTED",
+            IsolationLevel::RepeatableRead => "REPEATABLE READ",
+            IsolationLevel::Serializable => "SERIALIZABLE"
+        }
+    }
+}
+
+/// Settings of a transaction opened by `transaction_with`. PostgreSQL only
+/// accepts an isolation level before the first query of the outermost
+/// transaction, so nested calls should leave it `None`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TransactionOptions {
+    /// `None` keeps the session default.
+    pub isolation_level: Option<IsolationLevel>,
+    pub read_only: bool
+}
+
+/// Runs `f` in a transaction that is committed if `f` returns `Ok` and rolled
+/// back if it returns `Err` or panics. Called with a `Transaction`, e.g. from
+/// inside another `transaction`, it uses a savepoint instead.
+///
+/// ```ignore
+/// try!(transaction(&*cn, |tx| {
+///     try!(try_exec!(&jedi.create_query(), tx, &[]));
+///     try_exec!(&padawan.update_query(), tx, &[])
+/// }));
+/// ```
+pub fn transaction<T, F>(cn: &GenericConnection, f: F) -> ::error::Result<T>
+        where F: FnOnce(&Transaction) -> ::error::Result<T> {
+    transaction_with(cn, &TransactionOptions::default(), f)
+}
+
+pub fn transaction_with<T, F>(cn: &GenericConnection, options: &TransactionOptions, f: F) -> ::error::Result<T>
+        where F: FnOnce(&Transaction) -> ::error::Result<T> {
+    // Dropping an unfinished transaction, also while unwinding, rolls it back.
+    let tx = try!(cn.transaction());
+
+    let mut modes = vec![];
+    if let Some(level) = options.isolation_level {
+        modes.push(format!("ISOLATION LEVEL {}", level.to_sql()));
+    }
+    if options.read_only {
+        modes.push("READ ONLY".to_string());
+    }
+    if !modes.is_empty() {
+        try!(tx.batch_execute(&format!("SET TRANSACTION {};", modes.connect(", "))));
+    }
+
+    let result = f(&tx);
+    if result.is_ok() {
+        try!(tx.commit());
+    }
+
+    result
+}
+
+/// Told about every retry of `retry_transaction`: the attempt that is about
+/// to start (1 for the first retry), the wait before it and the error that
+/// made the previous attempt fail.
+pub trait RetryObserver {
+    fn retry(&self, attempt: u32, delay_ms: u32, err: &::error::Error);
+}
+
+impl<F: Fn(u32, u32, &::error::Error)> RetryObserver for F {
+    fn retry(&self, attempt: u32, delay_ms: u32, err: &::error::Error) {
+        self(attempt, delay_ms, err)
+    }
+}
+
+pub struct RetryPolicy {
+    /// How many times a failed transaction is run again.
+    pub max_retries: u32,
+    /// Wait before the first retry, doubled for every further one.
+    pub backoff_ms: u32,
+    pub max_backoff_ms: u32,
+    /// Serializable by default.
+    pub transaction: TransactionOptions,
+    pub observer: Option<Box<RetryObserver>>
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 3,
+            backoff_ms: 10,
+            max_backoff_ms: 1000,
+            transaction: TransactionOptions {
+                isolation_level: Some(IsolationLevel::Serializable),
+                read_only: false
+            },
+            observer: None
+        }
+    }
+}
+
+/// Like `transaction_with`, but runs `f` again in a new transaction when it
+/// fails with a serialization failure or a deadlock, up to
+/// `policy.max_retries` times. Retrying only helps for a whole transaction,
+/// so don't call it with a `Transaction`.
+pub fn retry_transaction<T, F>(cn: &GenericConnection, policy: &RetryPolicy, mut f: F) -> ::error::Result<T>
+        where F: FnMut(&Transaction) -> ::error::Result<T> {
+    let mut attempt = 0;
+
+    loop {
+        match transaction_with(cn, &policy.transaction, |tx| f(tx)) {
+            Err(ref err) if attempt < policy.max_retries && err.sql_kind().map_or(false, |kind| kind.is_transient()) => {
+                let delay_ms = cmp::min(policy.backoff_ms.saturating_mul(1 << cmp::min(attempt, 16)), policy.max_backoff_ms);
+                attempt += 1;
+
+                if let Some(ref observer) = policy.observer {
+                    observer.retry(attempt, delay_ms, err);
+                }
+
+                thread::sleep_ms(delay_ms);
+            },
+            result => return result
+        }
+    }
+}
+
+/// Error of the `try_*` query macros.
+pub type OrmError = ::error::Error;
+
+pub trait FromRow: Sized {
     fn from_row<T, L>(query: &::deuterium::SelectQuery<T, L, Self>, row: &::postgres::rows::Row) -> Self;
+
+    /// Like `from_row`, but reports columns that can't be read instead of panicking.
+    fn try_from_row<T, L>(query: &::deuterium::SelectQuery<T, L, Self>, row: &::postgres::rows::Row) -> ::error::Result<Self> {
+        Ok(FromRow::from_row(query, row))
+    }
 }
 
 pub fn from_row<T, L, M: FromRow>(query: &::deuterium::SelectQuery<T, L, M>, row: &::postgres::rows::Row) -> M {
     FromRow::from_row(query, row)
 }
 
+pub fn try_from_row<T, L, M: FromRow>(query: &::deuterium::SelectQuery<T, L, M>, row: &::postgres::rows::Row) -> ::error::Result<M> {
+    FromRow::try_from_row(query, row)
+}
+
 #[macro_export]
 macro_rules! to_sql_string_pg {
     ($query:expr) => ({
@@ -102,6 +247,29 @@ macro_rules! query_pg {
     });
 }
 
+/// Like `query_pg!`, but evaluates to `Result<_, deuterium_orm::Error>` instead
+/// of panicking. `$blk` evaluates to a `Result` as well.
+#[macro_export]
+macro_rules! try_query_pg {
+    ($query:expr, $cn:expr, $params:expr, $rows:ident, $blk:block) => ({
+        let (ctx, maybe_stm) = ::deuterium_orm::adapter::postgres::PostgresAdapter::prepare_query($query, $cn);
+        let orm_error = |e: ::postgres::Error| ::deuterium_orm::Error::query(
+            to_sql_string_pg!($query), file!(), line!(), e
+        );
+
+        match maybe_stm {
+            Ok(stm) => {
+                let result = match ::deuterium_orm::adapter::postgres::PostgresAdapter::query(&stm, $params, ctx.data()) {
+                    Ok($rows) => $blk,
+                    Err(e) => Err(orm_error(e))
+                };
+                result
+            },
+            Err(e) => Err(orm_error(e))
+        }
+    });
+}
+
 #[macro_export]
 macro_rules! query_models_iter {
     ($query:expr, $cn:expr, $params:expr) => (
@@ -136,12 +304,50 @@ macro_rules! query_model {
     )
 }
 
+#[macro_export]
+macro_rules! try_query_models {
+    ($query:expr, $cn:expr, $params:expr) => (
+        try_query_pg!($query, $cn, $params, rows, {
+            rows.iter().map(|row| {
+                ::deuterium_orm::adapter::postgres::try_from_row($query, &row)
+            }).collect::<Result<Vec<_>, _>>()
+        })
+    )
+}
+
+#[macro_export]
+macro_rules! try_query_model {
+    ($query:expr, $cn:expr, $params:expr) => (
+        try_query_pg!($query, $cn, $params, rows, {
+            match rows.iter().take(1).next() {
+                Some(row) => ::deuterium_orm::adapter::postgres::try_from_row($query, &row).map(Some),
+                None => Ok(None)
+            }
+        })
+    )
+}
+
 #[macro_export]
 macro_rules! exec_pg_safe {
     ($query:expr, $cn:expr, $params:expr) => ({
         let (ctx, maybe_stm) = ::deuterium_orm::adapter::postgres::PostgresAdapter::prepare_query($query, $cn);
-        let stm = maybe_stm.unwrap();
-        ::deuterium_orm::adapter::postgres::PostgresAdapter::execute(&stm, $params, ctx.data())
+        let result = maybe_stm.and_then(|stm| {
+            ::deuterium_orm::adapter::postgres::PostgresAdapter::execute(&stm, $params, ctx.data())
+        });
+        result
+    })
+}
+
+/// Like `exec_pg!`, but evaluates to `Result<u64, deuterium_orm::Error>` instead of panicking.
+#[macro_export]
+macro_rules! try_exec {
+    ($query:expr, $cn:expr, $params:expr) => ({
+        match exec_pg_safe!($query, $cn, $params) {
+            Ok(res) => Ok(res),
+            Err(e) => Err(::deuterium_orm::Error::query(
+                to_sql_string_pg!($query), file!(), line!(), e
+            ))
+        }
     })
 }
 
@@ -162,7 +368,7 @@ macro_rules! try_pg {
     ($e:expr) => (
         match $e {
             Ok(ok) => ok,
-            Err(err) => return Err(::postgres::Error::IoError(err))
+            Err(err) => return Err(::std::convert::From::from(err))
         }
     )
 }
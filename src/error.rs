@@ -0,0 +1,213 @@
+use std::error;
+use std::fmt;
+use std::io;
+use postgres;
+use postgres::error::SqlState;
+use r2d2;
+use r2d2_postgres;
+
+use migration::MigrationError;
+
+/// Why the database rejected a statement, derived from its SQLSTATE.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SqlErrorKind {
+    /// 23505, with the name of the violated constraint.
+    UniqueViolation { constraint: Option<String> },
+    /// 23503
+    ForeignKeyViolation { constraint: Option<String> },
+    /// 23502, with the column that was null.
+    NotNullViolation { column: Option<String> },
+    /// 23514
+    CheckViolation { constraint: Option<String> },
+    /// 40001, the transaction can be retried.
+    SerializationFailure,
+    /// 40P01, the transaction can be retried.
+    Deadlock,
+    /// 57014, which is how an exceeded `statement_timeout` is reported.
+    StatementTimeout,
+    Other
+}
+
+impl SqlErrorKind {
+    pub fn of(err: &postgres::Error) -> SqlErrorKind {
+        let err = match *err {
+            postgres::Error::DbError(ref err) => err,
+            _ => return SqlErrorKind::Other
+        };
+        let constraint = err.constraint().map(|c| c.to_string());
+
+        match *err.code() {
+            SqlState::UniqueViolation => SqlErrorKind::UniqueViolation { constraint: constraint },
+            SqlState::ForeignKeyViolation => SqlErrorKind::ForeignKeyViolation { constraint: constraint },
+            SqlState::NotNullViolation => SqlErrorKind::NotNullViolation { column: err.column().map(|c| c.to_string()) },
+            SqlState::CheckViolation => SqlErrorKind::CheckViolation { constraint: constraint },
+            SqlState::TRSerializationFailure => SqlErrorKind::SerializationFailure,
+            SqlState::TRDeadlockDetected => SqlErrorKind::Deadlock,
+            SqlState::QueryCanceled => SqlErrorKind::StatementTimeout,
+            _ => SqlErrorKind::Other
+        }
+    }
+
+    /// `true` for failures that go away when the transaction is run again.
+    pub fn is_transient(&self) -> bool {
+        match *self {
+            SqlErrorKind::SerializationFailure | SqlErrorKind::Deadlock => true,
+            _ => false
+        }
+    }
+}
+
+/// A statement the database rejected.
+#[derive(Debug)]
+pub struct SqlError {
+    pub kind: SqlErrorKind,
+    /// The statement as it was sent, when it is known.
+    pub sql: Option<String>,
+    /// `file!()` and `line!()` of the query macro that ran it.
+    pub location: Option<(&'static str, u32)>,
+    pub error: postgres::Error
+}
+
+impl SqlError {
+    pub fn new(sql: Option<String>, location: Option<(&'static str, u32)>, error: postgres::Error) -> SqlError {
+        SqlError {
+            kind: SqlErrorKind::of(&error),
+            sql: sql,
+            location: location,
+            error: error
+        }
+    }
+
+    /// SQLSTATE reported by the server. `None` for client-side failures.
+    pub fn code(&self) -> Option<&SqlState> {
+        match self.error {
+            postgres::Error::DbError(ref err) => Some(err.code()),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Opening a connection failed.
+    Connect(postgres::ConnectError),
+    /// The connection pool couldn't be created.
+    Pool(r2d2_postgres::Error),
+    /// No pooled connection became available in time.
+    PoolTimeout(r2d2::GetTimeout),
+    Sql(SqlError),
+    /// A column couldn't be read as the type of the model field.
+    Decode {
+        column: String,
+        expected: &'static str,
+        error: postgres::Error
+    },
+    /// The model has no primary key value to build an update or delete for.
+    MissingPrimaryKey {
+        table: &'static str
+    },
+    /// The field was not selected by the query the model was loaded with.
+    UnloadedField {
+        table: &'static str,
+        field: &'static str
+    },
+    Migration(MigrationError)
+}
+
+impl Error {
+    /// Failure of `sql`, issued by a query macro at `file`:`line`.
+    pub fn query(sql: String, file: &'static str, line: u32, error: postgres::Error) -> Error {
+        Error::Sql(SqlError::new(Some(sql), Some((file, line)), error))
+    }
+
+    /// Kind of a database error, `None` for errors that didn't come from a statement.
+    pub fn sql_kind(&self) -> Option<&SqlErrorKind> {
+        match *self {
+            Error::Sql(ref err) => Some(&err.kind),
+            _ => None
+        }
+    }
+}
+
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+impl From<postgres::Error> for Error {
+    fn from(err: postgres::Error) -> Error {
+        Error::Sql(SqlError::new(None, None, err))
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::from(postgres::Error::IoError(err))
+    }
+}
+
+impl From<postgres::ConnectError> for Error {
+    fn from(err: postgres::ConnectError) -> Error {
+        Error::Connect(err)
+    }
+}
+
+impl From<r2d2_postgres::Error> for Error {
+    fn from(err: r2d2_postgres::Error) -> Error {
+        Error::Pool(err)
+    }
+}
+
+impl From<r2d2::GetTimeout> for Error {
+    fn from(err: r2d2::GetTimeout) -> Error {
+        Error::PoolTimeout(err)
+    }
+}
+
+impl From<MigrationError> for Error {
+    fn from(err: MigrationError) -> Error {
+        Error::Migration(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Connect(ref err) => write!(f, "can't connect to the database: {}", err),
+            Error::Pool(ref err) => write!(f, "can't create the connection pool: {}", err),
+            Error::PoolTimeout(ref err) => write!(f, "{}", err),
+            Error::Sql(SqlError { sql: Some(ref sql), location: Some((file, line)), ref error, .. }) =>
+                write!(f, "SQL query `{}` failed at {}:{} with error `{}`", sql, file, line, error),
+            Error::Sql(ref err) => write!(f, "SQL error: {}", err.error),
+            Error::Decode { ref column, expected, ref error } =>
+                write!(f, "can't read column `{}` as `{}`: {}", column, expected, error),
+            Error::MissingPrimaryKey { table } => write!(f, "`{}` model has no primary key value", table),
+            Error::UnloadedField { table, field } => write!(f, "field `{}` of `{}` model was not loaded", field, table),
+            Error::Migration(ref err) => write!(f, "{}", err)
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Connect(_) => "connection error",
+            Error::Pool(_) => "connection pool error",
+            Error::PoolTimeout(_) => "connection pool timeout",
+            Error::Sql(_) => "SQL error",
+            Error::Decode { .. } => "column decoding error",
+            Error::MissingPrimaryKey { .. } => "missing primary key",
+            Error::UnloadedField { .. } => "unloaded field",
+            Error::Migration(_) => "migration error"
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::Connect(ref err) => Some(err),
+            Error::Pool(ref err) => Some(err),
+            Error::PoolTimeout(ref err) => Some(err),
+            Error::Sql(ref err) => Some(&err.error),
+            Error::Decode { ref error, .. } => Some(error),
+            Error::Migration(ref err) => Some(err),
+            Error::MissingPrimaryKey { .. } | Error::UnloadedField { .. } => None
+        }
+    }
+}
@@ -18,6 +18,8 @@ extern crate num;
 
 #[cfg(feature = "postgres")]
 pub use adapter::postgres::*;
+pub use error::{Error, SqlErrorKind};
 
 #[macro_use] pub mod adapter;
 #[macro_use] pub mod migration;
+pub mod error;
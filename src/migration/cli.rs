@@ -0,0 +1,174 @@
+//! Command-line front end for the migration runner. Call `cli::main` from
+//! the project's own `main`, passing the list built by `load_migrations!`:
+//!
+//! ```ignore
+//! fn main() {
+//!     deuterium_orm::migration::cli::main(&migrations(), PathBuf::from("src/migrations"));
+//! }
+//! ```
+//!
+//! The connection string is read from `--connection` or `POSTGRES_CONNECTION`.
+
+use std::env;
+use std::io::{self, Write};
+use std::path;
+use std::process;
+use postgres;
+
+use super::{Migrations, MigrationConnection, Config, ChecksumPolicy, OutOfOrderPolicy, SilentReporter};
+
+const USAGE: &'static str = "Usage: <command> [options]
+
+Commands:
+    new NAME [--sql]    create a migration file
+    up                  apply every pending migration
+    down [N]            revert the last N migrations (default 1)
+    redo                revert the last migration and apply it again
+    status              list migrations and whether they are applied
+
+Options:
+    --connection URL    database to migrate (default: $POSTGRES_CONNECTION)
+    --dry-run           print what would run and roll everything back
+    --quiet             don't report progress
+    --lock-timeout MS   give up waiting for the migration lock after MS
+    --allow-modified    only warn when applied migrations were modified
+    --out-of-order P    allow, warn (default) or refuse out-of-order migrations
+    --schema-dump PATH  write the resulting schema to PATH after a change
+    --table NAME        migrations table (default: schema_migrations)
+    --schema NAME       schema holding the migrations table
+    --search-path PATH  search_path to run migrations with";
+
+pub enum Command {
+    New { name: String, sql: bool },
+    Up,
+    Down(usize),
+    Redo,
+    Status
+}
+
+pub struct Options {
+    pub command: Command,
+    pub connection: Option<String>,
+    pub config: Config
+}
+
+pub fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut positional = vec![];
+    let mut connection = None;
+    let mut sql = false;
+    let mut config = Config::default();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "--connection" => {
+                connection = Some(try!(args.next().ok_or("--connection requires a value".to_string())).clone());
+            },
+            "--lock-timeout" => {
+                let timeout = try!(args.next().ok_or("--lock-timeout requires a value".to_string()));
+                config.lock_timeout_ms = Some(try!(timeout.parse().map_err(|_| format!("invalid lock timeout `{}`", timeout))));
+            },
+            "--out-of-order" => {
+                let policy = try!(args.next().ok_or("--out-of-order requires a value".to_string()));
+                config.out_of_order_policy = match &policy[..] {
+                    "allow" => OutOfOrderPolicy::Allow,
+                    "warn" => OutOfOrderPolicy::Warn,
+                    "refuse" => OutOfOrderPolicy::Refuse,
+                    policy => return Err(format!("invalid out-of-order policy `{}`", policy))
+                };
+            },
+            "--schema-dump" => {
+                let dump = try!(args.next().ok_or("--schema-dump requires a value".to_string()));
+                config.schema_dump = Some(path::PathBuf::from(dump));
+            },
+            "--table" => {
+                config.table = try!(args.next().ok_or("--table requires a value".to_string())).clone();
+            },
+            "--schema" => {
+                config.schema = Some(try!(args.next().ok_or("--schema requires a value".to_string())).clone());
+            },
+            "--search-path" => {
+                config.search_path = Some(try!(args.next().ok_or("--search-path requires a value".to_string())).clone());
+            },
+            "--dry-run" => config.dry_run = true,
+            "--quiet" => config.reporter = Box::new(SilentReporter),
+            "--allow-modified" => config.checksum_policy = ChecksumPolicy::Warn,
+            "--sql" => sql = true,
+            _ => positional.push(&arg[..])
+        }
+    }
+
+    let command = match (positional.first().map(|c| *c), positional.len()) {
+        (Some("new"), 2) => Command::New { name: positional[1].to_string(), sql: sql },
+        (Some("up"), 1) => Command::Up,
+        (Some("down"), 1) => Command::Down(1),
+        (Some("down"), 2) => {
+            let steps = positional[1];
+            Command::Down(try!(steps.parse().map_err(|_| format!("invalid step count `{}`", steps))))
+        },
+        (Some("redo"), 1) => Command::Redo,
+        (Some("status"), 1) => Command::Status,
+        _ => return Err(USAGE.to_string())
+    };
+
+    Ok(Options {
+        command: command,
+        connection: connection,
+        config: config
+    })
+}
+
+fn connect<Conn: From<postgres::Connection>>(connection: Option<String>) -> Result<Conn, String> {
+    let connection = match connection.or(env::var("POSTGRES_CONNECTION").ok()) {
+        Some(connection) => connection,
+        None => return Err("Please provide --connection or POSTGRES_CONNECTION".to_string())
+    };
+
+    postgres::Connection::connect(&connection[..], &postgres::SslMode::None)
+        .map(Conn::from)
+        .map_err(|e| e.to_string())
+}
+
+/// `Conn` is `postgres::Connection` unless the migrations are written against
+/// a wrapper such as `RecordingConnection`.
+pub fn exec<Conn>(args: &[String], migrations: &Migrations<Conn>, migrations_path: path::PathBuf) -> Result<(), String>
+        where Conn: MigrationConnection + From<postgres::Connection> {
+    let options = try!(parse_args(args));
+
+    if let Command::New { ref name, sql } = options.command {
+        let created = if sql {
+            super::create_sql_migration_file(name, migrations_path)
+        } else {
+            super::create_migration_file(name, migrations_path)
+        };
+
+        let created = try!(created.map_err(|e| e.to_string()));
+        println!("Created {}", created.display());
+        return Ok(());
+    }
+
+    let cn: Conn = try!(connect(options.connection));
+    let config = &options.config;
+
+    let result = match options.command {
+        Command::Up => super::run(migrations, &cn, config).map(|_| ()),
+        Command::Down(steps) => super::rollback(steps, migrations, &cn, config).map(|_| ()),
+        Command::Redo => super::redo(migrations, &cn, config).map(|_| ()),
+        Command::Status => super::status(migrations, &cn, config).map(|status| print!("{}", status)),
+        Command::New { .. } => unreachable!()
+    };
+
+    result.map_err(|e| e.to_string())
+}
+
+/// Runs the command given on the process command line and exits with a
+/// non-zero status on failure.
+pub fn main<Conn>(migrations: &Migrations<Conn>, migrations_path: path::PathBuf)
+        where Conn: MigrationConnection + From<postgres::Connection> {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    if let Err(err) = exec(&args, migrations, migrations_path) {
+        let _ = writeln!(io::stderr(), "{}", err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,365 @@
+use std::cell::RefCell;
+use std::error;
+use std::ops::Deref;
+use postgres;
+use postgres::GenericConnection;
+use postgres::rows::Row;
+use postgres::types::ToSql;
+
+use adapter::postgres::PostgresPooledConnection;
+use super::{Config, AppliedMigration, LOCK_CLASS, quote_ident, dump};
+
+/// Connection the migration runner can drive: statements, the migrations
+/// table, the lock that keeps runners apart and transactions around each
+/// migration. Every `PostgresConnection` implements it; connections to
+/// other databases can implement it with their own `Error`.
+pub trait MigrationConnection {
+    type Error: error::Error + 'static;
+
+    fn batch_execute(&self, sql: &str) -> Result<(), Self::Error>;
+
+    fn begin(&self) -> Result<(), Self::Error> { self.batch_execute("BEGIN;") }
+    fn commit(&self) -> Result<(), Self::Error> { self.batch_execute("COMMIT;") }
+    fn rollback(&self) -> Result<(), Self::Error> { self.batch_execute("ROLLBACK;") }
+
+    /// Whether the transaction opened by `begin` is still open, checked after
+    /// each migration in case it committed or rolled back on its own.
+    fn in_transaction(&self) -> Result<bool, Self::Error> { Ok(true) }
+
+    /// Creates the migrations table, or adds the columns tables created by
+    /// older versions of the runner are missing.
+    fn ensure_versions_table(&self, config: &Config) -> Result<(), Self::Error>;
+
+    /// Rows of the migrations table, newest first. Must not change the
+    /// database: a missing table has no rows and missing columns read as `None`.
+    fn query_versions(&self, config: &Config) -> Result<Vec<AppliedMigration>, Self::Error>;
+
+    fn insert_version(&self, config: &Config, version: i64, name: &str, checksum: Option<&str>,
+                      duration_ms: i64) -> Result<(), Self::Error>;
+
+    /// Deletes `version` from the migrations table, and every older version too
+    /// if `older` is set.
+    fn delete_versions(&self, config: &Config, version: i64, older: bool) -> Result<(), Self::Error>;
+
+    /// Takes the lock that keeps runners using the same migrations table
+    /// apart. Without `wait` it returns `false` instead of waiting for
+    /// another session to release the lock.
+    fn lock(&self, config: &Config, wait: bool) -> Result<bool, Self::Error>;
+    fn unlock(&self, config: &Config) -> Result<(), Self::Error>;
+
+    /// Sets the search path and returns the previous one.
+    fn set_search_path(&self, search_path: &str) -> Result<String, Self::Error>;
+
+    /// Dump written to `Config::schema_dump`.
+    fn dump_schema(&self, config: &Config) -> Result<String, Self::Error>;
+
+    /// Starts keeping the SQL of the statements migrations execute.
+    /// Returns `false` if the connection can't record.
+    fn start_recording(&self) -> bool { false }
+
+    /// Stops recording and returns the recorded statements.
+    fn stop_recording(&self) -> Vec<String> { vec![] }
+}
+
+/// PostgreSQL connection, as used by the migration runner and `dump`.
+pub trait PostgresConnection {
+    fn execute(&self, sql: &str, params: &[&ToSql]) -> postgres::Result<u64>;
+    fn batch_execute(&self, sql: &str) -> postgres::Result<()>;
+
+    /// Calls `f` with every row returned by `sql`.
+    fn query(&self, sql: &str, params: &[&ToSql], f: &mut FnMut(Row)) -> postgres::Result<()>;
+
+    /// Has to set `deuterium.migration` to `on` for the transaction, see
+    /// `MigrationConnection::in_transaction`.
+    fn begin(&self) -> postgres::Result<()> { self.batch_execute("BEGIN; SET LOCAL deuterium.migration = 'on';") }
+    fn commit(&self) -> postgres::Result<()> { self.batch_execute("COMMIT;") }
+    fn rollback(&self) -> postgres::Result<()> { self.batch_execute("ROLLBACK;") }
+
+    /// See `MigrationConnection::start_recording`.
+    fn start_recording(&self) -> bool { false }
+    fn stop_recording(&self) -> Vec<String> { vec![] }
+}
+
+/// Columns of the migrations table after `version`, with their types. Tables
+/// created by older versions of the runner may lack some of them.
+const VERSIONS_COLUMNS: [(&'static str, &'static str); 4] = [
+    ("name", "VARCHAR"),
+    ("applied_at", "TIMESTAMPTZ"),
+    ("duration_ms", "BIGINT"),
+    ("checksum", "VARCHAR")
+];
+
+/// Columns the migrations table has, empty if it doesn't exist.
+fn versions_table_columns<C: PostgresConnection>(cn: &C, config: &Config) -> postgres::Result<Vec<String>> {
+    let mut columns = vec![];
+    try!(cn.query("SELECT column_name::text FROM information_schema.columns
+        WHERE table_schema = COALESCE($1::text, current_schema()::text) AND table_name = $2;",
+        &[&config.schema, &config.table], &mut |row| columns.push(row.get(0))));
+
+    Ok(columns)
+}
+
+impl<C: PostgresConnection> MigrationConnection for C {
+    type Error = postgres::Error;
+
+    fn batch_execute(&self, sql: &str) -> postgres::Result<()> { PostgresConnection::batch_execute(self, sql) }
+    fn begin(&self) -> postgres::Result<()> { PostgresConnection::begin(self) }
+    fn commit(&self) -> postgres::Result<()> { PostgresConnection::commit(self) }
+    fn rollback(&self) -> postgres::Result<()> { PostgresConnection::rollback(self) }
+
+    // The setting `begin` made goes back to unset when the transaction ends,
+    // whoever ended it.
+    fn in_transaction(&self) -> postgres::Result<bool> {
+        let mut open = false;
+        try!(self.query("SELECT COALESCE(current_setting('deuterium.migration', true), '') = 'on';", &[],
+            &mut |row| open = row.get(0)));
+        Ok(open)
+    }
+
+    fn ensure_versions_table(&self, config: &Config) -> postgres::Result<()> {
+        if let Some(ref schema) = config.schema {
+            try!(self.execute(&format!("CREATE SCHEMA IF NOT EXISTS {};", quote_ident(schema)), &[]));
+        }
+
+        try!(self.execute(&format!("CREATE TABLE IF NOT EXISTS {} (
+             version BIGINT NOT NULL,
+             name VARCHAR,
+             applied_at TIMESTAMPTZ,
+             duration_ms BIGINT,
+             checksum VARCHAR
+        );", config.table_name()), &[]));
+
+        let columns = try!(versions_table_columns(self, config));
+        for &(column, ty) in VERSIONS_COLUMNS.iter() {
+            if !columns.iter().any(|c| c == column) {
+                try!(self.execute(&format!("ALTER TABLE {} ADD COLUMN {} {};", config.table_name(), column, ty), &[]));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn query_versions(&self, config: &Config) -> postgres::Result<Vec<AppliedMigration>> {
+        let columns = try!(versions_table_columns(self, config));
+        if columns.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let select: Vec<String> = VERSIONS_COLUMNS.iter().map(|&(column, ty)| {
+            if columns.iter().any(|c| c == column) { column.to_string() } else { format!("NULL::{}", ty) }
+        }).collect();
+
+        let mut applied = vec![];
+        try!(self.query(&format!("SELECT version, {} FROM {} ORDER BY version desc;",
+            select.connect(", "), config.table_name()), &[], &mut |row| {
+            applied.push(AppliedMigration {
+                version: row.get(0),
+                name: row.get(1),
+                applied_at: row.get(2),
+                duration_ms: row.get(3),
+                checksum: row.get(4)
+            });
+        }));
+
+        Ok(applied)
+    }
+
+    fn insert_version(&self, config: &Config, version: i64, name: &str, checksum: Option<&str>,
+                      duration_ms: i64) -> postgres::Result<()> {
+        try!(self.execute(&format!("INSERT INTO {} (version, name, applied_at, duration_ms, checksum)
+            VALUES ($1, $2, CURRENT_TIMESTAMP, $3, $4);", config.table_name()),
+            &[&version, &name, &duration_ms, &checksum]));
+        Ok(())
+    }
+
+    fn delete_versions(&self, config: &Config, version: i64, older: bool) -> postgres::Result<()> {
+        let operator = if older { "<=" } else { "=" };
+        try!(self.execute(&format!("DELETE FROM {} WHERE version {} $1;", config.table_name(), operator), &[&version]));
+        Ok(())
+    }
+
+    // Session-level advisory lock keyed by the migrations table name.
+    fn lock(&self, config: &Config, wait: bool) -> postgres::Result<bool> {
+        let table = config.table_name();
+
+        if wait {
+            try!(self.execute("SELECT pg_advisory_lock($1, hashtext($2));", &[&LOCK_CLASS, &table]));
+            return Ok(true);
+        }
+
+        let mut locked = false;
+        try!(self.query("SELECT pg_try_advisory_lock($1, hashtext($2));", &[&LOCK_CLASS, &table],
+            &mut |row| locked = row.get(0)));
+        Ok(locked)
+    }
+
+    fn unlock(&self, config: &Config) -> postgres::Result<()> {
+        try!(self.execute("SELECT pg_advisory_unlock($1, hashtext($2));", &[&LOCK_CLASS, &config.table_name()]));
+        Ok(())
+    }
+
+    fn set_search_path(&self, search_path: &str) -> postgres::Result<String> {
+        let mut previous = String::new();
+        try!(self.query("SELECT current_setting('search_path');", &[], &mut |row| previous = row.get(0)));
+        try!(self.execute("SELECT set_config('search_path', $1, false);", &[&search_path]));
+        Ok(previous)
+    }
+
+    fn dump_schema(&self, config: &Config) -> postgres::Result<String> {
+        dump::dump_schema(self, config)
+    }
+
+    fn start_recording(&self) -> bool { PostgresConnection::start_recording(self) }
+    fn stop_recording(&self) -> Vec<String> { PostgresConnection::stop_recording(self) }
+}
+
+fn query_rows<C: GenericConnection>(cn: &C, sql: &str, params: &[&ToSql], f: &mut FnMut(Row)) -> postgres::Result<()> {
+    let stmt = try!(cn.prepare(sql));
+
+    for row in try!(stmt.query(params)) {
+        f(row);
+    }
+
+    Ok(())
+}
+
+impl PostgresConnection for postgres::Connection {
+    fn execute(&self, sql: &str, params: &[&ToSql]) -> postgres::Result<u64> {
+        postgres::Connection::execute(self, sql, params)
+    }
+
+    fn batch_execute(&self, sql: &str) -> postgres::Result<()> {
+        postgres::Connection::batch_execute(self, sql)
+    }
+
+    fn query(&self, sql: &str, params: &[&ToSql], f: &mut FnMut(Row)) -> postgres::Result<()> {
+        query_rows(self, sql, params, f)
+    }
+}
+
+/// Inside an open transaction each migration runs in a savepoint, so the
+/// runner never commits the caller's transaction.
+impl<'conn> PostgresConnection for postgres::Transaction<'conn> {
+    fn execute(&self, sql: &str, params: &[&ToSql]) -> postgres::Result<u64> {
+        postgres::Transaction::execute(self, sql, params)
+    }
+
+    fn batch_execute(&self, sql: &str) -> postgres::Result<()> {
+        postgres::Transaction::batch_execute(self, sql)
+    }
+
+    fn query(&self, sql: &str, params: &[&ToSql], f: &mut FnMut(Row)) -> postgres::Result<()> {
+        query_rows(self, sql, params, f)
+    }
+
+    fn begin(&self) -> postgres::Result<()> {
+        postgres::Transaction::batch_execute(self,
+            "SAVEPOINT deuterium_migration; SET LOCAL deuterium.migration = 'on';")
+    }
+
+    fn commit(&self) -> postgres::Result<()> {
+        postgres::Transaction::batch_execute(self, "RELEASE SAVEPOINT deuterium_migration;")
+    }
+
+    fn rollback(&self) -> postgres::Result<()> {
+        postgres::Transaction::batch_execute(self,
+            "ROLLBACK TO SAVEPOINT deuterium_migration; RELEASE SAVEPOINT deuterium_migration;")
+    }
+}
+
+impl<'a> PostgresConnection for PostgresPooledConnection<'a> {
+    fn execute(&self, sql: &str, params: &[&ToSql]) -> postgres::Result<u64> {
+        postgres::Connection::execute(&**self, sql, params)
+    }
+
+    fn batch_execute(&self, sql: &str) -> postgres::Result<()> {
+        postgres::Connection::batch_execute(&**self, sql)
+    }
+
+    fn query(&self, sql: &str, params: &[&ToSql], f: &mut FnMut(Row)) -> postgres::Result<()> {
+        query_rows(&**self, sql, params, f)
+    }
+}
+
+/// Delegates to `C` and can record the SQL of `execute` and `batch_execute`
+/// calls. Migrations written against it instead of `C` get the statements
+/// they execute printed in dry-run mode. Statements run through methods of
+/// `C` itself, e.g. `prepare`, `query` or `transaction`, are not recorded.
+pub struct RecordingConnection<C = postgres::Connection> {
+    inner: C,
+    recorded: RefCell<Option<Vec<String>>>
+}
+
+impl<C> RecordingConnection<C> {
+    pub fn new(inner: C) -> RecordingConnection<C> {
+        RecordingConnection {
+            inner: inner,
+            recorded: RefCell::new(None)
+        }
+    }
+
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+
+    fn record(&self, sql: &str) {
+        if let Some(ref mut recorded) = *self.recorded.borrow_mut() {
+            recorded.push(sql.to_string());
+        }
+    }
+}
+
+// Inherent, so that they are picked over the methods of `C` reached through
+// `Deref` even when no connection trait is imported.
+impl<C: PostgresConnection> RecordingConnection<C> {
+    pub fn execute(&self, sql: &str, params: &[&ToSql]) -> postgres::Result<u64> {
+        self.record(sql);
+        self.inner.execute(sql, params)
+    }
+
+    pub fn batch_execute(&self, sql: &str) -> postgres::Result<()> {
+        self.record(sql);
+        PostgresConnection::batch_execute(&self.inner, sql)
+    }
+}
+
+impl From<postgres::Connection> for RecordingConnection {
+    fn from(cn: postgres::Connection) -> RecordingConnection {
+        RecordingConnection::new(cn)
+    }
+}
+
+impl<C> Deref for RecordingConnection<C> {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        &self.inner
+    }
+}
+
+impl<C: PostgresConnection> PostgresConnection for RecordingConnection<C> {
+    fn execute(&self, sql: &str, params: &[&ToSql]) -> postgres::Result<u64> {
+        RecordingConnection::execute(self, sql, params)
+    }
+
+    fn batch_execute(&self, sql: &str) -> postgres::Result<()> {
+        RecordingConnection::batch_execute(self, sql)
+    }
+
+    fn query(&self, sql: &str, params: &[&ToSql], f: &mut FnMut(Row)) -> postgres::Result<()> {
+        self.inner.query(sql, params, f)
+    }
+
+    fn begin(&self) -> postgres::Result<()> { PostgresConnection::begin(&self.inner) }
+    fn commit(&self) -> postgres::Result<()> { PostgresConnection::commit(&self.inner) }
+    fn rollback(&self) -> postgres::Result<()> { PostgresConnection::rollback(&self.inner) }
+
+    fn start_recording(&self) -> bool {
+        *self.recorded.borrow_mut() = Some(vec![]);
+        true
+    }
+
+    fn stop_recording(&self) -> Vec<String> {
+        self.recorded.borrow_mut().take().unwrap_or(vec![])
+    }
+}
@@ -0,0 +1,302 @@
+//! Deterministic dump of the current schema, built from `pg_catalog`, and a
+//! loader that recreates a database from it without replaying migrations.
+
+use std::fs;
+use std::io::{Read, Write};
+use std::path;
+use postgres;
+
+use super::{Config, MigrationResult, MigrationError, PostgresConnection, MigrationTransaction, SearchPath, quote_ident};
+
+const HEADER: &'static str = "-- Schema dump generated by deuterium_orm. Do not edit by hand.\n";
+
+fn dump_types<Conn: PostgresConnection>(cn: &Conn, schema: &str, out: &mut String) -> postgres::Result<()> {
+    // Enums, domains and standalone composite types, in creation order so
+    // that a type is defined before the types built on it.
+    let mut types = vec![];
+
+    try!(cn.query("SELECT t.typtype::text, quote_ident(t.typname),
+            CASE t.typtype
+                WHEN 'e' THEN (SELECT string_agg(quote_literal(e.enumlabel), ', ' ORDER BY e.enumsortorder)
+                    FROM pg_enum e WHERE e.enumtypid = t.oid)
+                WHEN 'c' THEN (SELECT string_agg(quote_ident(a.attname) || ' ' || format_type(a.atttypid, a.atttypmod),
+                        ', ' ORDER BY a.attnum)
+                    FROM pg_attribute a WHERE a.attrelid = t.typrelid AND a.attnum > 0 AND NOT a.attisdropped)
+                ELSE format_type(t.typbasetype, t.typtypmod)
+            END,
+            t.typdefault, t.typnotnull,
+            (SELECT string_agg(' CONSTRAINT ' || quote_ident(con.conname) || ' ' || pg_get_constraintdef(con.oid),
+                    '' ORDER BY con.conname)
+                FROM pg_constraint con WHERE con.contypid = t.oid AND con.contype = 'c')
+        FROM pg_type t
+        JOIN pg_namespace n ON n.oid = t.typnamespace
+        LEFT JOIN pg_class c ON c.oid = t.typrelid
+        WHERE n.nspname = $1
+            AND (t.typtype IN ('e', 'd') OR (t.typtype = 'c' AND c.relkind = 'c'))
+            AND NOT EXISTS (SELECT 1 FROM pg_depend d
+                WHERE d.classid = 'pg_type'::regclass AND d.objid = t.oid AND d.deptype = 'e')
+        ORDER BY t.oid;", &[&schema], &mut |row| {
+        let kind: String = row.get(0);
+        let name: String = row.get(1);
+        let body: Option<String> = row.get(2);
+        let body = body.unwrap_or(String::new());
+
+        let definition = match &kind[..] {
+            "e" => format!("CREATE TYPE {} AS ENUM ({});\n", name, body),
+            "c" => format!("CREATE TYPE {} AS ({});\n", name, body),
+            _ => {
+                let default: Option<String> = row.get(3);
+                let not_null: bool = row.get(4);
+                let checks: Option<String> = row.get(5);
+
+                let mut definition = format!("CREATE DOMAIN {} AS {}", name, body);
+                if let Some(default) = default {
+                    definition.push_str(&format!(" DEFAULT {}", default));
+                }
+                if not_null {
+                    definition.push_str(" NOT NULL");
+                }
+                definition.push_str(&checks.unwrap_or(String::new()));
+                definition + ";\n"
+            }
+        };
+        types.push(definition);
+    }));
+
+    if !types.is_empty() {
+        out.push_str(&types.concat());
+        out.push_str("\n");
+    }
+
+    Ok(())
+}
+
+fn dump_sequences<Conn: PostgresConnection>(cn: &Conn, schema: &str, out: &mut String) -> postgres::Result<()> {
+    // Sequences of identity columns are created with the column.
+    cn.query("SELECT quote_ident(s.relname), format_type(q.seqtypid, NULL), q.seqstart, q.seqincrement,
+            q.seqmin, q.seqmax, q.seqcache, q.seqcycle
+        FROM pg_class s
+        JOIN pg_namespace n ON n.oid = s.relnamespace
+        JOIN pg_sequence q ON q.seqrelid = s.oid
+        WHERE s.relkind = 'S' AND n.nspname = $1
+            AND NOT EXISTS (SELECT 1 FROM pg_depend d
+                WHERE d.classid = 'pg_class'::regclass AND d.objid = s.oid AND d.deptype = 'i')
+        ORDER BY s.relname;", &[&schema], &mut |row| {
+        let name: String = row.get(0);
+        let ty: String = row.get(1);
+        let start: i64 = row.get(2);
+        let increment: i64 = row.get(3);
+        let min: i64 = row.get(4);
+        let max: i64 = row.get(5);
+        let cache: i64 = row.get(6);
+        let cycle: bool = row.get(7);
+        out.push_str(&format!("CREATE SEQUENCE {} AS {} START WITH {} INCREMENT BY {} MINVALUE {} MAXVALUE {} CACHE {}{};\n",
+            name, ty, start, increment, min, max, cache, if cycle { " CYCLE" } else { "" }));
+    })
+}
+
+fn dump_tables<Conn: PostgresConnection>(cn: &Conn, schema: &str, out: &mut String) -> postgres::Result<()> {
+    let mut tables: Vec<(String, Vec<String>)> = vec![];
+
+    try!(cn.query("SELECT quote_ident(c.relname), quote_ident(a.attname), format_type(a.atttypid, a.atttypmod),
+            a.attnotnull, pg_get_expr(d.adbin, d.adrelid), a.attidentity::text
+        FROM pg_attribute a
+        JOIN pg_class c ON c.oid = a.attrelid
+        JOIN pg_namespace n ON n.oid = c.relnamespace
+        LEFT JOIN pg_attrdef d ON d.adrelid = a.attrelid AND d.adnum = a.attnum
+        WHERE n.nspname = $1 AND c.relkind = 'r' AND a.attnum > 0 AND NOT a.attisdropped
+        ORDER BY c.relname, a.attnum;", &[&schema], &mut |row| {
+        let table: String = row.get(0);
+        let column: String = row.get(1);
+        let ty: String = row.get(2);
+        let not_null: bool = row.get(3);
+        let default: Option<String> = row.get(4);
+        let identity: String = row.get(5);
+
+        let mut definition = format!("    {} {}", column, ty);
+        if let Some(default) = default {
+            definition.push_str(&format!(" DEFAULT {}", default));
+        }
+        match &identity[..] {
+            "a" => definition.push_str(" GENERATED ALWAYS AS IDENTITY"),
+            "d" => definition.push_str(" GENERATED BY DEFAULT AS IDENTITY"),
+            _ => ()
+        }
+        if not_null {
+            definition.push_str(" NOT NULL");
+        }
+
+        let is_new_table = tables.last().map_or(true, |&(ref name, _)| *name != table);
+        if is_new_table {
+            tables.push((table, vec![]));
+        }
+        tables.last_mut().unwrap().1.push(definition);
+    }));
+
+    for (table, columns) in tables.into_iter() {
+        out.push_str(&format!("\nCREATE TABLE {} (\n{}\n);\n", table, columns.connect(",\n")));
+    }
+
+    Ok(())
+}
+
+fn dump_sequence_owners<Conn: PostgresConnection>(cn: &Conn, schema: &str, out: &mut String) -> postgres::Result<()> {
+    let mut owners = vec![];
+
+    try!(cn.query("SELECT quote_ident(s.relname), quote_ident(t.relname), quote_ident(a.attname)
+        FROM pg_class s
+        JOIN pg_namespace n ON n.oid = s.relnamespace
+        JOIN pg_depend d ON d.classid = 'pg_class'::regclass AND d.objid = s.oid
+            AND d.refclassid = 'pg_class'::regclass AND d.deptype = 'a'
+        JOIN pg_class t ON t.oid = d.refobjid
+        JOIN pg_attribute a ON a.attrelid = d.refobjid AND a.attnum = d.refobjsubid
+        WHERE s.relkind = 'S' AND n.nspname = $1
+        ORDER BY s.relname;", &[&schema], &mut |row| {
+        let sequence: String = row.get(0);
+        let table: String = row.get(1);
+        let column: String = row.get(2);
+        owners.push(format!("ALTER SEQUENCE {} OWNED BY {}.{};\n", sequence, table, column));
+    }));
+
+    if !owners.is_empty() {
+        out.push_str("\n");
+        out.push_str(&owners.concat());
+    }
+
+    Ok(())
+}
+
+fn dump_constraints<Conn: PostgresConnection>(cn: &Conn, schema: &str, out: &mut String) -> postgres::Result<()> {
+    // Foreign keys go last, after the keys they reference exist. NOT NULL
+    // constraints (`contype = 'n'` since PostgreSQL 18) are part of the columns.
+    let mut constraints = vec![];
+
+    try!(cn.query("SELECT quote_ident(c.relname), quote_ident(con.conname), pg_get_constraintdef(con.oid)
+        FROM pg_constraint con
+        JOIN pg_class c ON c.oid = con.conrelid
+        JOIN pg_namespace n ON n.oid = c.relnamespace
+        WHERE n.nspname = $1 AND con.contype IN ('p', 'u', 'f', 'c', 'x')
+        ORDER BY con.contype = 'f', c.relname, con.conname;", &[&schema], &mut |row| {
+        let table: String = row.get(0);
+        let name: String = row.get(1);
+        let definition: String = row.get(2);
+        constraints.push(format!("ALTER TABLE {} ADD CONSTRAINT {} {};\n", table, name, definition));
+    }));
+
+    if !constraints.is_empty() {
+        out.push_str("\n");
+        out.push_str(&constraints.concat());
+    }
+
+    Ok(())
+}
+
+fn dump_indexes<Conn: PostgresConnection>(cn: &Conn, schema: &str, out: &mut String) -> postgres::Result<()> {
+    // Indexes backing primary key and unique constraints are created by the constraints.
+    let mut indexes = vec![];
+
+    // `pg_get_indexdef` qualifies the table with its schema; the dump stays
+    // unqualified so that it can be loaded into any schema.
+    try!(cn.query("SELECT replace(pg_get_indexdef(i.indexrelid),
+            ' ON ' || quote_ident(n.nspname) || '.', ' ON ')
+        FROM pg_index i
+        JOIN pg_class ic ON ic.oid = i.indexrelid
+        JOIN pg_namespace n ON n.oid = ic.relnamespace
+        WHERE n.nspname = $1
+            AND NOT EXISTS (SELECT 1 FROM pg_constraint con WHERE con.conindid = i.indexrelid)
+        ORDER BY ic.relname;", &[&schema], &mut |row| {
+        let definition: String = row.get(0);
+        indexes.push(format!("{};\n", definition));
+    }));
+
+    if !indexes.is_empty() {
+        out.push_str("\n");
+        out.push_str(&indexes.concat());
+    }
+
+    Ok(())
+}
+
+fn dump_versions<Conn: PostgresConnection>(cn: &Conn, config: &Config, out: &mut String) -> postgres::Result<()> {
+    let mut values = vec![];
+
+    try!(cn.query(&format!("SELECT version, name, checksum FROM {} ORDER BY version;", config.table_name()), &[], &mut |row| {
+        let version: i64 = row.get(0);
+        let name: Option<String> = row.get(1);
+        let checksum: Option<String> = row.get(2);
+        values.push(format!("    ({}, {}, {})", version, quote(name), quote(checksum)));
+    }));
+
+    if !values.is_empty() {
+        out.push_str(&format!("\nINSERT INTO {} (version, name, checksum) VALUES\n{};\n",
+            config.table_name(), values.connect(",\n")));
+    }
+
+    Ok(())
+}
+
+fn quote(value: Option<String>) -> String {
+    match value {
+        Some(value) => format!("'{}'", value.replace("'", "''")),
+        None => "NULL".to_string()
+    }
+}
+
+/// Renders user-defined types, sequences, tables, constraints, indexes and
+/// the versions recorded in the migrations table of the configured schema
+/// (the current one if `config.schema` is `None`) as SQL.
+pub fn dump_schema<Conn: PostgresConnection>(cn: &Conn, config: &Config) -> postgres::Result<String> {
+    let mut schema = String::new();
+    try!(cn.query("SELECT COALESCE($1::text, current_schema()::text);", &[&config.schema],
+        &mut |row| schema = row.get(0)));
+
+    let mut out = HEADER.to_string();
+    out.push_str("\n");
+
+    // `load_schema` runs the dump in a transaction, so `SET LOCAL` doesn't
+    // outlive it.
+    if let Some(ref schema) = config.schema {
+        out.push_str(&format!("CREATE SCHEMA IF NOT EXISTS {0};\nSET LOCAL search_path TO {0};\n\n",
+            quote_ident(schema)));
+    }
+
+    // With the dumped schema first on the search path, defaults and foreign
+    // keys refer to its objects without qualifying them.
+    let quoted_schema = quote_ident(&schema);
+    let _search_path = try!(SearchPath::set(cn, Some(&quoted_schema[..])));
+
+    try!(dump_types(cn, &schema, &mut out));
+    try!(dump_sequences(cn, &schema, &mut out));
+    try!(dump_tables(cn, &schema, &mut out));
+    try!(dump_sequence_owners(cn, &schema, &mut out));
+    try!(dump_constraints(cn, &schema, &mut out));
+    try!(dump_indexes(cn, &schema, &mut out));
+    try!(dump_versions(cn, config, &mut out));
+
+    Ok(out)
+}
+
+/// Writes the connection's `MigrationConnection::dump_schema` to `path`.
+pub fn write_schema<Conn: super::MigrationConnection>(cn: &Conn, config: &Config, path: &path::Path)
+                    -> MigrationResult<(), Conn::Error> {
+    let dump = try!(cn.dump_schema(config));
+    try!(fs::File::create(path).and_then(|mut file| file.write_all(dump.as_bytes())).map_err(MigrationError::Io));
+
+    Ok(())
+}
+
+/// Builds the schema from a dump produced by `dump_schema` in a single
+/// transaction. Meant for fresh databases.
+pub fn load_schema<Conn: PostgresConnection>(cn: &Conn, dump: &str) -> postgres::Result<()> {
+    let trans = try!(MigrationTransaction::begin(cn, true));
+    try!(cn.batch_execute(dump));
+    trans.commit()
+}
+
+pub fn load_schema_file<Conn: PostgresConnection>(cn: &Conn, path: &path::Path) -> MigrationResult<()> {
+    let mut dump = String::new();
+    try!(fs::File::open(path).and_then(|mut f| f.read_to_string(&mut dump)).map_err(MigrationError::Io));
+    try!(load_schema(cn, &dump));
+
+    Ok(())
+}
@@ -1,10 +1,27 @@
-use num::ToPrimitive;
-use time::now_utc;
-use std::io::Write;
+use num::{ToPrimitive, FromPrimitive};
+use time::{now_utc, at_utc, precise_time_ns, Timespec};
+use std::io::{self, Write};
 use std::fs;
 use std::path;
 use postgres;
 use std::collections;
+use std::cmp;
+use std::thread;
+use std::error;
+use std::fmt;
+use std::ascii::AsciiExt;
+
+pub use self::sql::SqlMigration;
+pub use self::schema::ChangeMigration;
+pub use self::connection::{MigrationConnection, PostgresConnection, RecordingConnection};
+pub use self::reporter::{Reporter, StdoutReporter, SilentReporter};
+
+mod sql;
+mod connection;
+mod reporter;
+pub mod cli;
+pub mod schema;
+pub mod dump;
 
 pub fn gen_timecode() -> String {
     now_utc().strftime("%y%m%d%H%M%S").unwrap().to_string()
@@ -14,116 +31,945 @@ pub fn gen_full_name(name: &str) -> String {
     format!("_{}_{}", gen_timecode(), name)
 }
 
-pub fn create_migration_file(name: &str, base_path: path::PathBuf) -> String {
+/// Name of the `RawMigration` struct `load_migrations!` expects for `name`,
+/// e.g. `CreateUsers` for `create_users`.
+pub fn gen_struct_name(name: &str) -> String {
+    let upcaser = regex!(r"_([a-z])");
+    upcaser.replace_all(&format!("_{}", name), |caps: &::regex::Captures| {
+        caps.at(1).unwrap().to_ascii_uppercase()
+    })
+}
+
+/// Fails with `io::ErrorKind::AlreadyExists` rather than overwrite an existing migration.
+fn write_migration_file(final_path: path::PathBuf, contents: &str) -> io::Result<path::PathBuf> {
+    let mut file = try!(fs::OpenOptions::new().create_new(true).write(true).open(&final_path));
+    try!(file.write_all(contents.as_bytes()));
+
+    Ok(final_path)
+}
+
+/// Creates a Rust migration skeleton in `base_path` and returns its path.
+pub fn create_migration_file(name: &str, base_path: path::PathBuf) -> io::Result<path::PathBuf> {
     let full_name = gen_full_name(name);
     let final_path = base_path.join(&format!("{}.rs", full_name)[..]);
 
-    let mut file = match fs::OpenOptions::new().create(true).write(true).open(&final_path) {
-        Ok(f) => f,
-        Err(e) => panic!("file error: {}", e),
-    };
+    let contents = format!(r#"use postgres;
+use deuterium_orm::migration::RawMigration;
+
+pub struct {name};
+
+impl RawMigration<postgres::Connection> for {name} {{
+    fn up(&self, _cn: &postgres::Connection) -> postgres::Result<()> {{
+        Ok(())
+    }}
+
+    fn down(&self, _cn: &postgres::Connection) -> postgres::Result<()> {{
+        Ok(())
+    }}
+}}
+"#, name = gen_struct_name(name));
+
+    write_migration_file(final_path, &contents)
+}
+
+/// Creates a plain SQL migration with `-- up` and `-- down` sections in
+/// `base_path` and returns its path.
+pub fn create_sql_migration_file(name: &str, base_path: path::PathBuf) -> io::Result<path::PathBuf> {
+    let full_name = gen_full_name(name);
+    let final_path = base_path.join(&format!("{}.sql", full_name)[..]);
 
-    file.write_all(b"").unwrap();
-    full_name
+    write_migration_file(final_path, "-- up\n\n-- down\n")
 }
 
-pub struct Migration<Conn> {
+pub struct Migration<Conn: MigrationConnection> {
     version: u64,
     name: String,
+    checksum: Option<String>,
+    baseline: bool,
     raw: Box<RawMigration<Conn> + 'static>
 }
 
-impl<Conn> Migration<Conn> {
+impl<Conn: MigrationConnection> Migration<Conn> {
     pub fn new(version: u64, name: &str, raw: Box<RawMigration<Conn> + 'static>) -> Migration<Conn> {
         Migration {
             version: version,
             name: name.to_string(),
+            checksum: None,
+            baseline: false,
             raw: raw
         }
     }
 
+    /// Sets the checksum of the migration source, as computed by `load_migrations!`.
+    pub fn with_checksum(mut self, checksum: &str) -> Migration<Conn> {
+        self.checksum = Some(checksum.to_string());
+        self
+    }
+
+    /// Marks the migration as a baseline standing in for every older version.
+    /// A fresh database runs only the baseline; a database that already has
+    /// older versions runs the older migrations it is missing and gets the
+    /// baseline recorded as applied without running it.
+    pub fn baseline(mut self, baseline: bool) -> Migration<Conn> {
+        self.baseline = baseline;
+        self
+    }
+
     pub fn version(&self) -> &u64 { &self.version }
     pub fn name(&self) -> &str { &self.name }
+    pub fn checksum(&self) -> Option<&str> { self.checksum.as_ref().map(|c| &c[..]) }
+    pub fn is_baseline(&self) -> bool { self.baseline }
     pub fn raw(&self) -> &Box<RawMigration<Conn> + 'static> { &self.raw }
 }
 
-pub trait RawMigration<Conn> {
-    fn up(&self, cn: &Conn);
-    fn down(&self, cn: &Conn);
+/// `up` and `down` must not commit or roll back: they run inside the
+/// runner's transaction, and ending it (e.g. with `postgres::Connection::transaction`)
+/// would leave the `schema_migrations` update outside of it. The runner
+/// refuses such migrations with `MigrationError::TransactionEnded`.
+pub trait RawMigration<Conn: MigrationConnection> {
+    fn up(&self, cn: &Conn) -> Result<(), Conn::Error>;
+    fn down(&self, cn: &Conn) -> Result<(), Conn::Error>;
+
+    /// Whether `up`/`down` run inside a transaction together with the
+    /// `schema_migrations` update. Override to return `false` for statements
+    /// PostgreSQL refuses to run in a transaction (e.g. `CREATE INDEX CONCURRENTLY`).
+    fn transactional(&self) -> bool { true }
+
+    /// Why `down` can't be run, if it can't. The runner refuses to revert
+    /// such migrations.
+    fn irreversible(&self) -> Option<String> { None }
+
+    /// SQL run by `up`/`down`, printed in dry-run mode. When it is `None` and
+    /// the connection is a `RecordingConnection`, the statements the migration
+    /// executes are printed instead.
+    fn sql(&self, _direction: Direction) -> Option<String> { None }
+}
+
+pub type Migrations<Conn = postgres::Connection> = Vec<Box<Migration<Conn>>>;
+pub type MigrationRefs<'a, Conn = postgres::Connection> = Vec<&'a Box<Migration<Conn>>>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    Up,
+    Down
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Direction::Up => f.write_str("up"),
+            Direction::Down => f.write_str("down")
+        }
+    }
+}
+
+/// `E` is the error type of the `MigrationConnection` the runner was given.
+#[derive(Debug)]
+pub enum MigrationError<E = postgres::Error> {
+    /// The runner failed to read or update `schema_migrations`.
+    Database(E),
+    /// Reading or writing a schema dump failed.
+    Io(io::Error),
+    /// A migration failed. `completed` lists the versions processed before it.
+    Failed {
+        version: u64,
+        name: String,
+        direction: Direction,
+        error: E,
+        completed: Report
+    },
+    /// Versions recorded in `schema_migrations` with no matching migration,
+    /// so they can't be reverted.
+    Orphaned(Vec<u64>),
+    /// Applied migrations whose source changed since they were applied.
+    ChecksumMismatch(Vec<u64>),
+    /// Another process held the migration lock for longer than the timeout (in ms).
+    LockTimeout(u32),
+    /// A migration that has to be reverted has no usable `down`.
+    Irreversible {
+        version: u64,
+        name: String,
+        reason: String
+    },
+    /// Pending migrations older than the newest applied one.
+    OutOfOrder(Vec<u64>),
+    /// A migration committed or rolled back the runner's transaction. Its
+    /// version is not recorded.
+    TransactionEnded {
+        version: u64,
+        name: String
+    }
+}
+
+impl<E> MigrationError<E> {
+    fn failed<Conn: MigrationConnection>(migration: &Migration<Conn>, direction: Direction,
+              error: E, completed: Report) -> MigrationError<E> {
+        MigrationError::Failed {
+            version: *migration.version(),
+            name: migration.name().to_string(),
+            direction: direction,
+            error: error,
+            completed: completed
+        }
+    }
+}
+
+impl<E> From<E> for MigrationError<E> {
+    fn from(err: E) -> MigrationError<E> {
+        MigrationError::Database(err)
+    }
+}
+
+impl<E: error::Error> fmt::Display for MigrationError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MigrationError::Database(ref err) => write!(f, "schema_migrations error: {}", err),
+            MigrationError::Io(ref err) => write!(f, "schema dump error: {}", err),
+            MigrationError::Failed { version, ref name, direction, ref error, .. } =>
+                write!(f, "migration {} {} failed ({}): {}", version, name, direction, error),
+            MigrationError::Orphaned(ref versions) =>
+                write!(f, "applied versions without a matching migration: {:?}", versions),
+            MigrationError::ChecksumMismatch(ref versions) =>
+                write!(f, "applied migrations were modified: {:?}", versions),
+            MigrationError::LockTimeout(timeout) =>
+                write!(f, "could not acquire the migration lock within {}ms", timeout),
+            MigrationError::Irreversible { version, ref name, ref reason } =>
+                write!(f, "migration {} {} can't be reverted: {}", version, name, reason),
+            MigrationError::OutOfOrder(ref versions) =>
+                write!(f, "pending migrations are older than the newest applied one: {:?}", versions),
+            MigrationError::TransactionEnded { version, ref name } =>
+                write!(f, "migration {} {} ended the runner's transaction, migrations must not commit or roll back",
+                    version, name)
+        }
+    }
+}
+
+impl<E: error::Error> error::Error for MigrationError<E> {
+    fn description(&self) -> &str {
+        match *self {
+            MigrationError::Database(_) => "schema_migrations error",
+            MigrationError::Io(_) => "schema dump error",
+            MigrationError::Failed { .. } => "migration failed",
+            MigrationError::Orphaned(_) => "orphaned migration versions",
+            MigrationError::ChecksumMismatch(_) => "modified migrations",
+            MigrationError::LockTimeout(_) => "migration lock timeout",
+            MigrationError::Irreversible { .. } => "irreversible migration",
+            MigrationError::OutOfOrder(_) => "out-of-order migrations",
+            MigrationError::TransactionEnded { .. } => "migration ended the transaction"
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            MigrationError::Database(ref err) => Some(err),
+            MigrationError::Io(ref err) => Some(err),
+            MigrationError::Failed { ref error, .. } => Some(error),
+            MigrationError::Orphaned(_)
+            | MigrationError::ChecksumMismatch(_)
+            | MigrationError::LockTimeout(_)
+            | MigrationError::Irreversible { .. }
+            | MigrationError::OutOfOrder(_)
+            | MigrationError::TransactionEnded { .. } => None
+        }
+    }
+}
+
+pub type MigrationResult<T, E = postgres::Error> = Result<T, MigrationError<E>>;
+
+/// Versions a runner call applied and reverted, in the order it processed them.
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    pub applied: Vec<u64>,
+    pub reverted: Vec<u64>,
+    /// Pending migrations that were older than the newest applied version.
+    pub out_of_order: Vec<u64>,
+    /// Versions recorded as applied without running, because a baseline
+    /// covers them on a database that already had older versions.
+    pub marked_applied: Vec<u64>
+}
+
+/// What `run` does when an applied migration no longer matches the checksum
+/// recorded in `schema_migrations`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChecksumPolicy {
+    Refuse,
+    Warn,
+    Ignore
+}
+
+/// What `run` does with pending migrations older than the newest applied
+/// one, typically merged in from another branch.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutOfOrderPolicy {
+    Allow,
+    Warn,
+    Refuse
+}
+
+pub struct Config {
+    /// Name of the table that records applied versions.
+    pub table: String,
+    /// Schema holding `table`, created if missing. `None` uses the current
+    /// schema, the first one on the search path.
+    pub schema: Option<String>,
+    /// `search_path` used while the runner works, so that unqualified names in
+    /// migrations resolve to the service's schema. The previous value is
+    /// restored afterwards.
+    pub search_path: Option<String>,
+    pub checksum_policy: ChecksumPolicy,
+    pub out_of_order_policy: OutOfOrderPolicy,
+    /// How long to wait for another process to release the migration lock.
+    /// `None` waits forever.
+    pub lock_timeout_ms: Option<u32>,
+    /// Print what would be applied or reverted and roll everything back
+    /// at the end. Non-transactional migrations are printed but not executed.
+    pub dry_run: bool,
+    /// Where to write a `dump::dump_schema` of the database after every
+    /// successful run that changed it.
+    pub schema_dump: Option<path::PathBuf>,
+    /// Receives progress of the run. Prints to stdout by default.
+    pub reporter: Box<Reporter>
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            table: "schema_migrations".to_string(),
+            schema: None,
+            search_path: None,
+            checksum_policy: ChecksumPolicy::Refuse,
+            out_of_order_policy: OutOfOrderPolicy::Warn,
+            lock_timeout_ms: None,
+            dry_run: false,
+            schema_dump: None,
+            reporter: Box::new(StdoutReporter)
+        }
+    }
+}
+
+impl Config {
+    /// Quoted, schema-qualified name of the migrations table.
+    pub fn table_name(&self) -> String {
+        match self.schema {
+            Some(ref schema) => format!("{}.{}", quote_ident(schema), quote_ident(&self.table)),
+            None => quote_ident(&self.table)
+        }
+    }
+}
+
+fn quote_ident(ident: &str) -> String {
+    format!("\"{}\"", ident.replace("\"", "\"\""))
+}
+
+/// First half of the advisory lock key taken by the runner. The second half
+/// is the hash of the migrations table name, so runners with their own
+/// tables don't wait for each other.
+pub const LOCK_CLASS: i32 = 0x64657574; // "deut"
+const LOCK_POLL_MS: u32 = 100;
+
+/// Lock held for a whole run, so that concurrent processes don't apply the
+/// same migration twice. Released on drop, also when a migration fails.
+struct MigrationLock<'a, Conn: 'a + MigrationConnection> {
+    cn: &'a Conn,
+    config: &'a Config
+}
+
+impl<'a, Conn: MigrationConnection> MigrationLock<'a, Conn> {
+    fn acquire(cn: &'a Conn, config: &'a Config) -> MigrationResult<MigrationLock<'a, Conn>, Conn::Error> {
+        let timeout_ms = match config.lock_timeout_ms {
+            Some(timeout_ms) => timeout_ms,
+            None => {
+                try!(cn.lock(config, true));
+                return Ok(MigrationLock { cn: cn, config: config });
+            }
+        };
+
+        let started_at = precise_time_ns();
+
+        loop {
+            if try!(cn.lock(config, false)) {
+                return Ok(MigrationLock { cn: cn, config: config });
+            }
+
+            if (precise_time_ns() - started_at) / 1_000_000 >= timeout_ms as u64 {
+                return Err(MigrationError::LockTimeout(timeout_ms));
+            }
+
+            thread::sleep_ms(LOCK_POLL_MS);
+        }
+    }
+}
+
+impl<'a, Conn: MigrationConnection> Drop for MigrationLock<'a, Conn> {
+    fn drop(&mut self) {
+        let _ = self.cn.unlock(self.config);
+    }
+}
+
+/// Wraps a migration step in a transaction. If the step fails or panics
+/// before `commit` is called, the transaction is rolled back on drop.
+struct MigrationTransaction<'a, Conn: 'a + MigrationConnection> {
+    cn: &'a Conn,
+    active: bool
+}
+
+impl<'a, Conn: MigrationConnection> MigrationTransaction<'a, Conn> {
+    fn begin(cn: &'a Conn, transactional: bool) -> Result<MigrationTransaction<'a, Conn>, Conn::Error> {
+        if transactional {
+            try!(cn.begin());
+        }
+
+        Ok(MigrationTransaction {
+            cn: cn,
+            active: transactional
+        })
+    }
+
+    fn commit(mut self) -> Result<(), Conn::Error> {
+        if self.active {
+            self.active = false;
+            try!(self.cn.commit());
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a, Conn: MigrationConnection> Drop for MigrationTransaction<'a, Conn> {
+    fn drop(&mut self) {
+        if self.active {
+            let _ = self.cn.rollback();
+        }
+    }
+}
+
+/// Sets `search_path` while it lives and restores the previous value on drop,
+/// so that pooled connections go back to the pool unchanged.
+struct SearchPath<'a, Conn: 'a + MigrationConnection> {
+    cn: &'a Conn,
+    previous: Option<String>
+}
+
+impl<'a, Conn: MigrationConnection> SearchPath<'a, Conn> {
+    fn set(cn: &'a Conn, search_path: Option<&str>) -> Result<SearchPath<'a, Conn>, Conn::Error> {
+        let previous = match search_path {
+            Some(search_path) => Some(try!(cn.set_search_path(search_path))),
+            None => None
+        };
+
+        Ok(SearchPath { cn: cn, previous: previous })
+    }
+}
+
+impl<'a, Conn: MigrationConnection> Drop for SearchPath<'a, Conn> {
+    fn drop(&mut self) {
+        if let Some(ref previous) = self.previous {
+            let _ = self.cn.set_search_path(previous);
+        }
+    }
+}
+
+pub fn ensure_schema_migrations<Conn: MigrationConnection>(cn: &Conn, config: &Config) -> Result<(), Conn::Error> {
+    cn.ensure_versions_table(config)
+}
+
+pub fn insert_version<Conn: MigrationConnection>(version: &i64, name: &str, checksum: Option<&str>, duration_ms: &i64,
+                      cn: &Conn, config: &Config) -> Result<(), Conn::Error> {
+    cn.insert_version(config, *version, name, checksum, *duration_ms)
+}
+
+pub fn delete_version<Conn: MigrationConnection>(version: &i64, cn: &Conn, config: &Config) -> Result<(), Conn::Error> {
+    cn.delete_versions(config, *version, false)
+}
+
+/// Deletes `version` and every older version, used when a baseline is reverted.
+pub fn delete_versions_through<Conn: MigrationConnection>(version: &i64, cn: &Conn, config: &Config) -> Result<(), Conn::Error> {
+    cn.delete_versions(config, *version, true)
+}
+
+pub fn get_versions_as_hash<Conn: MigrationConnection>(cn: &Conn, config: &Config) -> Result<collections::HashMap<i64, bool>, Conn::Error> {
+    let applied = try!(cn.query_versions(config));
+    Ok(applied.iter().map(|a| (a.version, true)).collect())
 }
 
-pub type Migrations = Vec<Box<Migration<postgres::Connection>>>;
-pub type MigrationRefs<'a> = Vec<&'a Box<Migration<postgres::Connection>>>;
+pub fn get_versions_as_vec<Conn: MigrationConnection>(cn: &Conn, config: &Config) -> Result<Vec<i64>, Conn::Error> {
+    let applied = try!(cn.query_versions(config));
+    Ok(applied.iter().map(|a| a.version).collect())
+}
 
-pub fn ensure_schema_migrations(cn: &postgres::Connection) {
-    cn.execute("CREATE TABLE IF NOT EXISTS schema_migrations (
-         version BIGINT NOT NULL
-    );", &[]).unwrap();
+/// A row of the migrations table. Everything except `version` is `None` for
+/// versions recorded by older versions of the runner.
+pub struct AppliedMigration {
+    pub version: i64,
+    pub name: Option<String>,
+    pub applied_at: Option<Timespec>,
+    pub duration_ms: Option<i64>,
+    pub checksum: Option<String>
 }
 
-pub fn insert_version(version: &i64, cn: &postgres::Connection) {
-    cn.execute("INSERT INTO schema_migrations VALUES ($1);", &[version]).unwrap();
+pub fn get_applied_migrations<Conn: MigrationConnection>(cn: &Conn, config: &Config) -> Result<Vec<AppliedMigration>, Conn::Error> {
+    cn.query_versions(config)
 }
 
-pub fn delete_version(version: &i64, cn: &postgres::Connection) {
-    cn.execute("DELETE FROM schema_migrations WHERE version = $1;", &[version]).unwrap();
+/// Joins recorded statements into a script, one per line, under a note that
+/// statements run through other methods of the connection are missing.
+fn render_statements(statements: &[String]) -> String {
+    let mut lines = vec!["-- Recorded from execute/batch_execute, other statements are not shown".to_string()];
+    lines.extend(statements.iter().map(|statement| {
+        let statement = statement.trim();
+        if statement.ends_with(";") { statement.to_string() } else { format!("{};", statement) }
+    }));
+    lines.connect("\n")
 }
 
-pub fn get_versions_as_hash(cn: &postgres::Connection) -> collections::HashMap<i64, bool> {
-    let stmt = cn.prepare("SELECT version FROM schema_migrations ORDER BY version desc;").unwrap();
-    let rows = stmt.query(&[]).unwrap();
-    let mut db_versions: collections::HashMap<i64, bool> = collections::HashMap::new();
+/// Runs a migration in `direction` and returns how long it took in milliseconds.
+fn apply<Conn: MigrationConnection>(migration: &Migration<Conn>, direction: Direction, cn: &Conn,
+         config: &Config) -> MigrationResult<u64, Conn::Error> {
+    if direction == Direction::Down {
+        try!(check_reversible(migration));
+    }
+
+    let version = migration.version().to_i64().unwrap();
+    let transactional = migration.raw().transactional();
+
+    if config.dry_run && !transactional {
+        let sql = migration.raw().sql(direction);
+        config.reporter.preview(*migration.version(), migration.name(), direction,
+            sql.as_ref().map(|sql| &sql[..]), false);
+        return Ok(0);
+    }
+
+    // In dry-run mode the whole run already is inside a transaction.
+    let trans = try!(MigrationTransaction::begin(cn, transactional && !config.dry_run));
+    let started_at = precise_time_ns();
+
+    // Without SQL of its own, a dry-run migration shows what it executed.
+    let recording = config.dry_run && migration.raw().sql(direction).is_none() && cn.start_recording();
+    let result = match direction {
+        Direction::Up => migration.raw().up(cn),
+        Direction::Down => migration.raw().down(cn)
+    };
+    let recorded = if recording { Some(cn.stop_recording()) } else { None };
+    try!(result);
 
-    for row in rows {
-        db_versions.insert(row.get(0), true);
+    if transactional && !try!(cn.in_transaction()) {
+        return Err(MigrationError::TransactionEnded {
+            version: *migration.version(),
+            name: migration.name().to_string()
+        });
     }
 
-    db_versions
+    if config.dry_run {
+        let sql = migration.raw().sql(direction).or(recorded.map(|statements| render_statements(&statements)));
+        config.reporter.preview(*migration.version(), migration.name(), direction,
+            sql.as_ref().map(|sql| &sql[..]), true);
+    }
+
+    match direction {
+        Direction::Up => {
+            let duration_ms = ((precise_time_ns() - started_at) / 1_000_000).to_i64().unwrap();
+            try!(insert_version(&version, migration.name(), migration.checksum(), &duration_ms, cn, config));
+        },
+        Direction::Down => {
+            if migration.is_baseline() {
+                try!(delete_versions_through(&version, cn, config));
+            } else {
+                try!(delete_version(&version, cn, config));
+            }
+        }
+    }
+
+    try!(trans.commit());
+    Ok((precise_time_ns() - started_at) / 1_000_000)
+}
+
+/// Applied versions whose recorded checksum differs from the migration's.
+/// Versions recorded without a checksum are never reported.
+fn modified_versions<Conn: MigrationConnection>(migrations: &Migrations<Conn>, applied: &[AppliedMigration]) -> Vec<u64> {
+    migrations.iter().filter(|m| is_modified(m, applied)).map(|m| *m.version()).collect()
 }
 
-pub fn get_versions_as_vec(cn: &postgres::Connection) -> Vec<i64> {
-    let stmt = cn.prepare("SELECT version FROM schema_migrations ORDER BY version desc;").unwrap();
-    let rows = stmt.query(&[]).unwrap();
-    let mut db_versions: Vec<i64> = vec![];
+fn is_modified<Conn: MigrationConnection>(migration: &Migration<Conn>, applied: &[AppliedMigration]) -> bool {
+    let version = migration.version().to_i64().unwrap();
 
-    for row in rows {
-        db_versions.push(row.get(0));
+    match applied.iter().find(|a| a.version == version) {
+        Some(&AppliedMigration { checksum: Some(ref stored), .. }) =>
+            migration.checksum().map_or(false, |checksum| checksum != &stored[..]),
+        _ => false
     }
+}
+
+/// Refuses, warns about or ignores modified migrations according to the
+/// configured `ChecksumPolicy`.
+fn check_modified<Conn: MigrationConnection>(migrations: &Migrations<Conn>, applied: &[AppliedMigration], config: &Config) -> MigrationResult<(), Conn::Error> {
+    let modified = modified_versions(migrations, applied);
+
+    if !modified.is_empty() {
+        match config.checksum_policy {
+            ChecksumPolicy::Refuse => return Err(MigrationError::ChecksumMismatch(modified)),
+            ChecksumPolicy::Warn => config.reporter.warning(&format!("applied migrations were modified: {:?}", modified)),
+            ChecksumPolicy::Ignore => ()
+        }
+    }
+
+    Ok(())
+}
 
-    db_versions
+/// `down` is never called on a migration that reports itself irreversible.
+fn check_reversible<Conn: MigrationConnection>(migration: &Migration<Conn>) -> MigrationResult<(), Conn::Error> {
+    match migration.raw().irreversible() {
+        Some(reason) => Err(MigrationError::Irreversible {
+            version: *migration.version(),
+            name: migration.name().to_string(),
+            reason: reason
+        }),
+        None => Ok(())
+    }
 }
 
-pub fn run(migrations: &Migrations, cn: &postgres::Connection) {
-    ensure_schema_migrations(cn);
-    let db_versions = get_versions_as_hash(cn);
+/// Runs a single migration and records it in `report`.
+fn process<Conn: MigrationConnection>(migration: &Migration<Conn>, direction: Direction, cn: &Conn,
+           config: &Config, report: &mut Report) -> MigrationResult<(), Conn::Error> {
+    let (version, name) = (*migration.version(), migration.name());
+    config.reporter.start(version, name, direction);
+
+    match apply(migration, direction, cn, config) {
+        Ok(duration_ms) => config.reporter.finish(version, name, direction, duration_ms, config.dry_run),
+        Err(MigrationError::Database(err)) => {
+            config.reporter.failure(version, name, direction, &err);
+            return Err(MigrationError::failed(migration, direction, err, report.clone()));
+        },
+        Err(err) => {
+            config.reporter.failure(version, name, direction, &err);
+            return Err(err);
+        }
+    }
+
+    match direction {
+        Direction::Up => report.applied.push(*migration.version()),
+        Direction::Down => report.reverted.push(*migration.version())
+    }
+
+    Ok(())
+}
 
-    let migrations_to_run: MigrationRefs = migrations.iter().filter(|m| {
+/// Version of the newest baseline migration, if there is one.
+fn baseline_version<Conn: MigrationConnection>(migrations: &Migrations<Conn>) -> Option<i64> {
+    migrations.iter().filter(|m| m.is_baseline()).map(|m| m.version().to_i64().unwrap()).max()
+}
+
+/// On a database that already has versions older than the baseline, the
+/// older migrations it is missing followed by the baseline itself, which is
+/// recorded as applied without running it. Older baselines are recorded the
+/// same way, since they stand for versions the database already has.
+fn catch_up_migrations<'a, Conn: MigrationConnection>(baseline: i64, migrations: &'a Migrations<Conn>,
+                       applied: &[AppliedMigration]) -> MigrationRefs<'a, Conn> {
+    let existing = applied.iter().any(|a| a.version < baseline);
+    let baseline_applied = applied.iter().any(|a| a.version == baseline);
+
+    if !existing || baseline_applied {
+        return vec![];
+    }
+
+    migrations.iter().filter(|m| {
         let version = m.version().to_i64().unwrap();
-        !db_versions.contains_key(&version)
+        version <= baseline && !applied.iter().any(|a| a.version == version)
+    }).collect()
+}
+
+fn mark_applied<Conn: MigrationConnection>(migration: &Migration<Conn>, cn: &Conn, config: &Config,
+                report: &mut Report) -> MigrationResult<(), Conn::Error> {
+    let version = migration.version().to_i64().unwrap();
+    try!(insert_version(&version, migration.name(), migration.checksum(), &0, cn, config));
+    report.marked_applied.push(*migration.version());
+    config.reporter.mark_applied(*migration.version(), migration.name(), config.dry_run);
+
+    Ok(())
+}
+
+/// Applies every pending migration with a version up to `target`. Migrations
+/// older than a baseline only run on databases that already have older versions.
+fn forward<Conn: MigrationConnection>(target: Option<u64>, migrations: &Migrations<Conn>, applied: &[AppliedMigration], cn: &Conn,
+           config: &Config, report: &mut Report) -> MigrationResult<(), Conn::Error> {
+    let baseline = baseline_version(migrations);
+    let catch_up = match baseline {
+        Some(baseline) if target.map_or(true, |target| baseline <= target.to_i64().unwrap()) =>
+            catch_up_migrations(baseline, migrations, applied),
+        _ => vec![]
+    };
+
+    let migrations_to_run: MigrationRefs<Conn> = migrations.iter().filter(|m| {
+        let version = m.version().to_i64().unwrap();
+        !applied.iter().any(|a| a.version == version)
+            && !catch_up.iter().any(|c| c.version() == m.version())
+            && baseline.map_or(true, |baseline| version >= baseline)
+            && target.map_or(true, |target| *m.version() <= target)
     }).collect();
 
+    let newest = applied.iter().map(|a| a.version)
+        .filter(|v| target.map_or(true, |target| *v <= target.to_i64().unwrap()))
+        .max();
+    let out_of_order: Vec<u64> = catch_up.iter().filter(|m| !m.is_baseline())
+        .chain(migrations_to_run.iter())
+        .filter(|m| newest.map_or(false, |newest| m.version().to_i64().unwrap() < newest))
+        .map(|m| *m.version())
+        .collect();
+
+    if !out_of_order.is_empty() {
+        match config.out_of_order_policy {
+            OutOfOrderPolicy::Refuse => return Err(MigrationError::OutOfOrder(out_of_order)),
+            OutOfOrderPolicy::Warn => config.reporter.warning(&format!("applying migrations out of order: {:?}", out_of_order)),
+            OutOfOrderPolicy::Allow => ()
+        }
+    }
+    report.out_of_order = out_of_order;
+
+    for migration in catch_up.iter() {
+        if migration.is_baseline() {
+            try!(mark_applied(migration, cn, config, report));
+        } else {
+            try!(process(migration, Direction::Up, cn, config, report));
+        }
+    }
+
+    for migration in migrations_to_run.iter() {
+        try!(process(migration, Direction::Up, cn, config, report));
+    }
+
+    Ok(())
+}
+
+/// Reverts `versions` in the given order. Nothing is reverted if any of them
+/// has no matching migration or can't be reverted. Versions older than a
+/// baseline that is reverted too are undone by the baseline itself.
+fn revert<Conn: MigrationConnection>(versions: &[i64], migrations: &Migrations<Conn>, cn: &Conn,
+          config: &Config, report: &mut Report) -> MigrationResult<(), Conn::Error> {
+    let mut migrations_to_run: MigrationRefs<Conn> = vec![];
+    let mut orphaned = vec![];
+    let baseline = baseline_version(migrations).and_then(|baseline| {
+        if versions.contains(&baseline) { Some(baseline) } else { None }
+    });
+
+    for version in versions.iter() {
+        if baseline.map_or(false, |baseline| *version < baseline) {
+            continue;
+        }
+
+        match migrations.iter().find(|m| m.version().to_i64().unwrap() == *version) {
+            Some(migration) => migrations_to_run.push(migration),
+            None => orphaned.push(u64::from_i64(*version).unwrap())
+        }
+    }
+
+    if !orphaned.is_empty() {
+        return Err(MigrationError::Orphaned(orphaned));
+    }
+
     for migration in migrations_to_run.iter() {
-        migration.raw().up(cn);
-        insert_version(&migration.version().to_i64().unwrap(), cn);
+        try!(check_reversible(migration));
+    }
 
-        println!("Migration completed: {} {}", migration.version(), migration.name());
+    for migration in migrations_to_run.iter() {
+        try!(process(migration, Direction::Down, cn, config, report));
     }
+
+    Ok(())
+}
+
+/// Writes the configured schema dump if the run changed anything.
+fn finish<Conn: MigrationConnection>(report: Report, cn: &Conn, config: &Config) -> MigrationResult<Report, Conn::Error> {
+    let changed = !report.applied.is_empty() || !report.reverted.is_empty() || !report.marked_applied.is_empty();
+
+    if let Some(ref path) = config.schema_dump {
+        if changed && !config.dry_run {
+            try!(dump::write_schema(cn, config, path));
+        }
+    }
+
+    Ok(report)
+}
+
+/// Applies every pending migration (or reports what it would apply, in
+/// dry-run mode).
+pub fn run<Conn: MigrationConnection>(migrations: &Migrations<Conn>, cn: &Conn, config: &Config) -> MigrationResult<Report, Conn::Error> {
+    let _search_path = try!(SearchPath::set(cn, config.search_path.as_ref().map(|s| &s[..])));
+    let _lock = try!(MigrationLock::acquire(cn, config));
+    let _dry_run = try!(MigrationTransaction::begin(cn, config.dry_run));
+    try!(ensure_schema_migrations(cn, config));
+    let applied = try!(get_applied_migrations(cn, config));
+    try!(check_modified(migrations, &applied, config));
+
+    let mut report = Report::default();
+    try!(forward(None, migrations, &applied, cn, config, &mut report));
+
+    finish(report, cn, config)
+}
+
+/// Reverts the last `steps` applied migrations, newest first.
+pub fn rollback<Conn: MigrationConnection>(steps: usize, migrations: &Migrations<Conn>, cn: &Conn, config: &Config) -> MigrationResult<Report, Conn::Error> {
+    let _search_path = try!(SearchPath::set(cn, config.search_path.as_ref().map(|s| &s[..])));
+    let _lock = try!(MigrationLock::acquire(cn, config));
+    let _dry_run = try!(MigrationTransaction::begin(cn, config.dry_run));
+    try!(ensure_schema_migrations(cn, config));
+    let db_versions = try!(get_versions_as_vec(cn, config));
+    let steps = cmp::min(steps, db_versions.len());
+
+    let mut report = Report::default();
+    try!(revert(&db_versions[0..steps], migrations, cn, config, &mut report));
+
+    finish(report, cn, config)
+}
+
+/// Reverts every applied migration newer than `version`, newest first.
+pub fn rollback_to<Conn: MigrationConnection>(version: u64, migrations: &Migrations<Conn>, cn: &Conn, config: &Config) -> MigrationResult<Report, Conn::Error> {
+    let _search_path = try!(SearchPath::set(cn, config.search_path.as_ref().map(|s| &s[..])));
+    let _lock = try!(MigrationLock::acquire(cn, config));
+    let _dry_run = try!(MigrationTransaction::begin(cn, config.dry_run));
+    try!(ensure_schema_migrations(cn, config));
+    let target = version.to_i64().unwrap();
+    let db_versions: Vec<i64> = try!(get_versions_as_vec(cn, config)).into_iter()
+        .filter(|v| *v > target)
+        .collect();
+
+    let mut report = Report::default();
+    try!(revert(&db_versions, migrations, cn, config, &mut report));
+
+    finish(report, cn, config)
+}
+
+/// Reverts every applied migration newer than `version`, then applies every
+/// pending one up to and including it.
+pub fn migrate_to<Conn: MigrationConnection>(version: u64, migrations: &Migrations<Conn>, cn: &Conn, config: &Config) -> MigrationResult<Report, Conn::Error> {
+    let _search_path = try!(SearchPath::set(cn, config.search_path.as_ref().map(|s| &s[..])));
+    let _lock = try!(MigrationLock::acquire(cn, config));
+    let _dry_run = try!(MigrationTransaction::begin(cn, config.dry_run));
+    try!(ensure_schema_migrations(cn, config));
+    let applied = try!(get_applied_migrations(cn, config));
+    try!(check_modified(migrations, &applied, config));
+
+    let target = version.to_i64().unwrap();
+    let newer: Vec<i64> = applied.iter().map(|a| a.version).filter(|v| *v > target).collect();
+
+    let mut report = Report::default();
+    try!(revert(&newer, migrations, cn, config, &mut report));
+    try!(forward(Some(version), migrations, &applied, cn, config, &mut report));
+
+    finish(report, cn, config)
 }
 
-pub fn rollback(steps: usize, migrations: &Migrations, cn: &postgres::Connection) {
-    ensure_schema_migrations(cn);
-    let db_versions = get_versions_as_vec(cn);
-    let db_versions_to_run = &db_versions[0..steps];
+/// Reverts the newest applied migration and applies it again.
+pub fn redo<Conn: MigrationConnection>(migrations: &Migrations<Conn>, cn: &Conn, config: &Config) -> MigrationResult<Report, Conn::Error> {
+    let _search_path = try!(SearchPath::set(cn, config.search_path.as_ref().map(|s| &s[..])));
+    let _lock = try!(MigrationLock::acquire(cn, config));
+    let _dry_run = try!(MigrationTransaction::begin(cn, config.dry_run));
+    try!(ensure_schema_migrations(cn, config));
+    let db_versions = try!(get_versions_as_vec(cn, config));
 
-    let migrations_to_run: MigrationRefs = migrations.iter().filter(|m| {
+    let mut report = Report::default();
+    let latest = match db_versions.first() {
+        Some(latest) => *latest,
+        None => return Ok(report)
+    };
+
+    try!(revert(&[latest], migrations, cn, config, &mut report));
+
+    // `revert` fails on orphaned versions, so the migration exists here.
+    let migration = migrations.iter().find(|m| m.version().to_i64().unwrap() == latest).unwrap();
+    try!(process(migration, Direction::Up, cn, config, &mut report));
+
+    finish(report, cn, config)
+}
+
+#[derive(Clone, Debug)]
+pub struct MigrationStatus {
+    pub version: u64,
+    pub name: String,
+    pub applied: bool,
+    pub applied_at: Option<Timespec>,
+    /// `true` when the source changed after the migration was applied.
+    pub modified: bool
+}
+
+#[derive(Clone, Debug)]
+pub struct Status {
+    /// Every known migration in ascending version order.
+    pub migrations: Vec<MigrationStatus>,
+    /// Versions recorded in `schema_migrations` with no matching migration.
+    pub orphaned: Vec<u64>
+}
+
+impl Status {
+    pub fn pending(&self) -> Vec<&MigrationStatus> {
+        self.migrations.iter().filter(|m| !m.applied).collect()
+    }
+
+    /// `true` when every migration is applied unmodified and no orphaned versions exist.
+    pub fn is_up_to_date(&self) -> bool {
+        self.orphaned.is_empty() && self.migrations.iter().all(|m| m.applied && !m.modified)
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for migration in self.migrations.iter() {
+            let state = match (migration.applied, migration.modified) {
+                (true, true) => "up*",
+                (true, false) => "up",
+                (false, _) => "down"
+            };
+            let applied_at = match migration.applied_at {
+                Some(ts) => at_utc(ts).rfc3339().to_string(),
+                None => "-".to_string()
+            };
+
+            try!(writeln!(f, "{:<4} {} {:<40} {}", state, migration.version, migration.name, applied_at));
+        }
+
+        for version in self.orphaned.iter() {
+            try!(writeln!(f, "{:<4} {} {}", "up", version, "*** NO MIGRATION ***"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Compares `migrations` with the versions recorded in the migrations table.
+/// Only reads, so it works with read-only roles and on replicas; a missing
+/// migrations table means nothing is applied.
+pub fn status<Conn: MigrationConnection>(migrations: &Migrations<Conn>, cn: &Conn, config: &Config) -> MigrationResult<Status, Conn::Error> {
+    let _search_path = try!(SearchPath::set(cn, config.search_path.as_ref().map(|s| &s[..])));
+    let applied = try!(get_applied_migrations(cn, config));
+
+    // A fresh database only runs the baseline, which covers every older migration.
+    let baseline = baseline_version(migrations);
+    let covered_below = baseline.and_then(|baseline| {
+        if applied.iter().any(|a| a.version == baseline) { Some(baseline) } else { None }
+    });
+
+    let mut known: Vec<MigrationStatus> = migrations.iter().map(|m| {
         let version = m.version().to_i64().unwrap();
-        db_versions_to_run.contains(&version)
+        let record = applied.iter().find(|a| a.version == version);
+
+        MigrationStatus {
+            version: *m.version(),
+            name: m.name().to_string(),
+            applied: record.is_some() || covered_below.map_or(false, |baseline| version < baseline),
+            applied_at: record.and_then(|a| a.applied_at),
+            modified: is_modified(m, &applied)
+        }
     }).collect();
+    known.sort_by(|a, b| a.version.cmp(&b.version));
 
-    for migration in migrations_to_run.iter() {
-        migration.raw().down(cn);
-        delete_version(&migration.version().to_i64().unwrap(), cn);
+    // Versions older than a baseline are covered by it even without a migration.
+    let mut orphaned: Vec<u64> = applied.iter()
+        .filter(|a| baseline.map_or(true, |baseline| a.version > baseline))
+        .filter(|a| !migrations.iter().any(|m| m.version().to_i64().unwrap() == a.version))
+        .map(|a| u64::from_i64(a.version).unwrap())
+        .collect();
+    orphaned.sort();
 
-        println!("Migration reverted: {} {}", migration.version(), migration.name());
-    }
+    Ok(Status {
+        migrations: known,
+        orphaned: orphaned
+    })
 }
@@ -0,0 +1,75 @@
+//! Progress callbacks of the migration runner. `StdoutReporter` prints what
+//! the runner does, `SilentReporter` ignores everything. Implement
+//! `Reporter` to send progress to a logger instead.
+
+use std::error;
+
+use super::Direction;
+
+/// Every callback does nothing by default.
+pub trait Reporter {
+    /// A migration is about to be applied or reverted.
+    fn start(&self, _version: u64, _name: &str, _direction: Direction) {}
+
+    /// A migration was applied or reverted, or would have been in a dry run.
+    fn finish(&self, _version: u64, _name: &str, _direction: Direction, _duration_ms: u64, _dry_run: bool) {}
+
+    /// A migration failed. The run stops after this.
+    fn failure(&self, _version: u64, _name: &str, _direction: Direction, _err: &error::Error) {}
+
+    /// SQL of a migration in a dry run. `executed` is `false` for migrations
+    /// that can't run inside the dry-run transaction.
+    fn preview(&self, _version: u64, _name: &str, _direction: Direction, _sql: Option<&str>, _executed: bool) {}
+
+    /// A migration older than a baseline was recorded as applied without running it.
+    fn mark_applied(&self, _version: u64, _name: &str, _dry_run: bool) {}
+
+    /// Modified or out-of-order migrations the configured policy lets through.
+    fn warning(&self, _message: &str) {}
+}
+
+pub struct StdoutReporter;
+
+impl Reporter for StdoutReporter {
+    fn finish(&self, version: u64, name: &str, direction: Direction, duration_ms: u64, dry_run: bool) {
+        match (direction, dry_run) {
+            (Direction::Up, false) => println!("Migration completed: {} {} ({} ms)", version, name, duration_ms),
+            (Direction::Up, true) => println!("Migration would be applied: {} {}", version, name),
+            (Direction::Down, false) => println!("Migration reverted: {} {} ({} ms)", version, name, duration_ms),
+            (Direction::Down, true) => println!("Migration would be reverted: {} {}", version, name)
+        }
+    }
+
+    fn failure(&self, version: u64, name: &str, direction: Direction, err: &error::Error) {
+        println!("Migration failed: {} {} ({}): {}", version, name, direction, err);
+    }
+
+    fn preview(&self, version: u64, name: &str, direction: Direction, sql: Option<&str>, executed: bool) {
+        println!("-- {} {} ({})", version, name, direction);
+
+        match sql {
+            Some(sql) => println!("{}", sql),
+            None => println!("-- SQL is not available for this migration")
+        }
+
+        if !executed {
+            println!("-- Not executed: migration can't run inside a transaction");
+        }
+    }
+
+    fn mark_applied(&self, version: u64, name: &str, dry_run: bool) {
+        if dry_run {
+            println!("Migration would be marked as applied: {} {}", version, name);
+        } else {
+            println!("Migration marked as applied: {} {}", version, name);
+        }
+    }
+
+    fn warning(&self, message: &str) {
+        println!("Warning: {}", message);
+    }
+}
+
+pub struct SilentReporter;
+
+impl Reporter for SilentReporter {}
@@ -0,0 +1,478 @@
+use std::fmt;
+
+use super::{RawMigration, MigrationConnection, Direction, quote_ident};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Type {
+    SmallInt,
+    Integer,
+    BigInt,
+    Serial,
+    BigSerial,
+    Boolean,
+    Real,
+    Double,
+    Numeric(u32, u32),
+    Text,
+    Varchar(u32),
+    Date,
+    Timestamp,
+    Timestamptz,
+    Uuid,
+    Json,
+    Jsonb,
+    Bytea,
+    Custom(String)
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Type::SmallInt => f.write_str("smallint"),
+            Type::Integer => f.write_str("integer"),
+            Type::BigInt => f.write_str("bigint"),
+            Type::Serial => f.write_str("serial"),
+            Type::BigSerial => f.write_str("bigserial"),
+            Type::Boolean => f.write_str("boolean"),
+            Type::Real => f.write_str("real"),
+            Type::Double => f.write_str("double precision"),
+            Type::Numeric(precision, scale) => write!(f, "numeric({}, {})", precision, scale),
+            Type::Text => f.write_str("text"),
+            Type::Varchar(len) => write!(f, "varchar({})", len),
+            Type::Date => f.write_str("date"),
+            Type::Timestamp => f.write_str("timestamp"),
+            Type::Timestamptz => f.write_str("timestamptz"),
+            Type::Uuid => f.write_str("uuid"),
+            Type::Json => f.write_str("json"),
+            Type::Jsonb => f.write_str("jsonb"),
+            Type::Bytea => f.write_str("bytea"),
+            Type::Custom(ref ty) => f.write_str(ty)
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Column {
+    pub name: String,
+    pub ty: Type,
+    pub null: bool,
+    pub default: Option<String>,
+    pub primary_key: bool,
+    pub unique: bool
+}
+
+impl Column {
+    pub fn new(name: &str, ty: Type) -> Column {
+        Column {
+            name: name.to_string(),
+            ty: ty,
+            null: true,
+            default: None,
+            primary_key: false,
+            unique: false
+        }
+    }
+
+    pub fn not_null(mut self) -> Column {
+        self.null = false;
+        self
+    }
+
+    /// Sets the column default to the SQL expression `expr`.
+    pub fn default(mut self, expr: &str) -> Column {
+        self.default = Some(expr.to_string());
+        self
+    }
+
+    pub fn primary_key(mut self) -> Column {
+        self.primary_key = true;
+        self
+    }
+
+    pub fn unique(mut self) -> Column {
+        self.unique = true;
+        self
+    }
+
+    pub fn to_sql(&self) -> String {
+        let mut sql = format!("{} {}", quote_ident(&self.name), self.ty);
+
+        if self.primary_key { sql.push_str(" PRIMARY KEY"); }
+        if !self.null { sql.push_str(" NOT NULL"); }
+        if self.unique { sql.push_str(" UNIQUE"); }
+        if let Some(ref default) = self.default {
+            sql.push_str(&format!(" DEFAULT {}", default));
+        }
+
+        sql
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Index {
+    pub name: String,
+    pub table: String,
+    pub columns: Vec<String>,
+    pub unique: bool
+}
+
+/// `on_delete` is the referential action, e.g. `CASCADE` or `SET NULL`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ForeignKey {
+    pub name: String,
+    pub table: String,
+    pub column: String,
+    pub ref_table: String,
+    pub ref_column: String,
+    pub on_delete: Option<String>
+}
+
+/// A single schema change. Drops and type changes only know how to undo
+/// themselves when they carry the definition they replace. Names are quoted
+/// as single identifiers, so a table can't be given as `schema.table`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operation {
+    CreateTable { name: String, columns: Vec<Column> },
+    DropTable { name: String, columns: Option<Vec<Column>> },
+    RenameTable { from: String, to: String },
+    AddColumn { table: String, column: Column },
+    DropColumn { table: String, name: String, column: Option<Column> },
+    RenameColumn { table: String, from: String, to: String },
+    ChangeColumnType { table: String, column: String, to: Type, from: Option<Type> },
+    AddIndex(Index),
+    DropIndex { name: String, index: Option<Index> },
+    AddForeignKey(ForeignKey),
+    DropForeignKey { table: String, name: String, foreign_key: Option<ForeignKey> },
+    Sql(String)
+}
+
+impl Operation {
+    pub fn to_sql(&self) -> String {
+        match *self {
+            Operation::CreateTable { ref name, ref columns } => {
+                let columns: Vec<String> = columns.iter().map(|c| format!("    {}", c.to_sql())).collect();
+                format!("CREATE TABLE {} (\n{}\n);", quote_ident(name), columns.connect(",\n"))
+            },
+            Operation::DropTable { ref name, .. } =>
+                format!("DROP TABLE {};", quote_ident(name)),
+            Operation::RenameTable { ref from, ref to } =>
+                format!("ALTER TABLE {} RENAME TO {};", quote_ident(from), quote_ident(to)),
+            Operation::AddColumn { ref table, ref column } =>
+                format!("ALTER TABLE {} ADD COLUMN {};", quote_ident(table), column.to_sql()),
+            Operation::DropColumn { ref table, ref name, .. } =>
+                format!("ALTER TABLE {} DROP COLUMN {};", quote_ident(table), quote_ident(name)),
+            Operation::RenameColumn { ref table, ref from, ref to } =>
+                format!("ALTER TABLE {} RENAME COLUMN {} TO {};", quote_ident(table), quote_ident(from), quote_ident(to)),
+            Operation::ChangeColumnType { ref table, ref column, ref to, .. } =>
+                format!("ALTER TABLE {} ALTER COLUMN {} TYPE {};", quote_ident(table), quote_ident(column), to),
+            Operation::AddIndex(ref index) => {
+                let columns: Vec<String> = index.columns.iter().map(|c| quote_ident(c)).collect();
+                format!("CREATE {}INDEX {} ON {} ({});",
+                    if index.unique { "UNIQUE " } else { "" }, quote_ident(&index.name), quote_ident(&index.table), columns.connect(", "))
+            },
+            Operation::DropIndex { ref name, .. } =>
+                format!("DROP INDEX {};", quote_ident(name)),
+            Operation::AddForeignKey(ref fk) => {
+                let mut sql = format!("ALTER TABLE {} ADD CONSTRAINT {} FOREIGN KEY ({}) REFERENCES {} ({})",
+                    quote_ident(&fk.table), quote_ident(&fk.name), quote_ident(&fk.column),
+                    quote_ident(&fk.ref_table), quote_ident(&fk.ref_column));
+                if let Some(ref on_delete) = fk.on_delete {
+                    sql.push_str(&format!(" ON DELETE {}", on_delete));
+                }
+                sql + ";"
+            },
+            Operation::DropForeignKey { ref table, ref name, .. } =>
+                format!("ALTER TABLE {} DROP CONSTRAINT {};", quote_ident(table), quote_ident(name)),
+            Operation::Sql(ref sql) => sql.clone()
+        }
+    }
+
+    /// The operation that undoes this one, or `None` if it can't be derived.
+    pub fn reverse(&self) -> Option<Operation> {
+        match *self {
+            Operation::CreateTable { ref name, ref columns } =>
+                Some(Operation::DropTable { name: name.clone(), columns: Some(columns.clone()) }),
+            Operation::DropTable { ref name, columns: Some(ref columns) } =>
+                Some(Operation::CreateTable { name: name.clone(), columns: columns.clone() }),
+            Operation::RenameTable { ref from, ref to } =>
+                Some(Operation::RenameTable { from: to.clone(), to: from.clone() }),
+            Operation::AddColumn { ref table, ref column } =>
+                Some(Operation::DropColumn { table: table.clone(), name: column.name.clone(), column: Some(column.clone()) }),
+            Operation::DropColumn { ref table, column: Some(ref column), .. } =>
+                Some(Operation::AddColumn { table: table.clone(), column: column.clone() }),
+            Operation::RenameColumn { ref table, ref from, ref to } =>
+                Some(Operation::RenameColumn { table: table.clone(), from: to.clone(), to: from.clone() }),
+            Operation::ChangeColumnType { ref table, ref column, ref to, from: Some(ref from) } =>
+                Some(Operation::ChangeColumnType { table: table.clone(), column: column.clone(), to: from.clone(), from: Some(to.clone()) }),
+            Operation::AddIndex(ref index) =>
+                Some(Operation::DropIndex { name: index.name.clone(), index: Some(index.clone()) }),
+            Operation::DropIndex { index: Some(ref index), .. } =>
+                Some(Operation::AddIndex(index.clone())),
+            Operation::AddForeignKey(ref fk) =>
+                Some(Operation::DropForeignKey { table: fk.table.clone(), name: fk.name.clone(), foreign_key: Some(fk.clone()) }),
+            Operation::DropForeignKey { foreign_key: Some(ref fk), .. } =>
+                Some(Operation::AddForeignKey(fk.clone())),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.to_sql())
+    }
+}
+
+/// Columns of a table being created with `Schema::create_table`.
+pub struct TableBuilder {
+    columns: Vec<Column>
+}
+
+impl TableBuilder {
+    pub fn column(&mut self, column: Column) -> &mut TableBuilder {
+        self.columns.push(column);
+        self
+    }
+
+    /// Shorthand for a `serial PRIMARY KEY` column named `id`.
+    pub fn id(&mut self) -> &mut TableBuilder {
+        self.column(Column::new("id", Type::Serial).primary_key())
+    }
+
+    /// Shorthand for `created_at`/`updated_at` columns, matching `#[before_create]`
+    /// and `#[before_save]` callbacks that set them.
+    pub fn timestamps(&mut self) -> &mut TableBuilder {
+        self.column(Column::new("created_at", Type::Timestamptz).not_null().default("CURRENT_TIMESTAMP"));
+        self.column(Column::new("updated_at", Type::Timestamptz).not_null().default("CURRENT_TIMESTAMP"))
+    }
+}
+
+/// Records schema operations and renders them as PostgreSQL DDL.
+///
+/// ```ignore
+/// let mut schema = Schema::new();
+/// schema.create_table("jedi", |t| {
+///     t.id();
+///     t.column(Column::new("name", Type::Varchar(40)).not_null());
+/// });
+/// schema.add_index("jedi", &["name"]);
+///
+/// try!(schema.run(cn));                   // up
+/// try!(schema.reverse().unwrap().run(cn)); // down
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Schema {
+    operations: Vec<Operation>
+}
+
+impl Schema {
+    pub fn new() -> Schema {
+        Schema { operations: vec![] }
+    }
+
+    pub fn push(&mut self, operation: Operation) -> &mut Schema {
+        self.operations.push(operation);
+        self
+    }
+
+    pub fn create_table<F>(&mut self, name: &str, f: F) -> &mut Schema where F: FnOnce(&mut TableBuilder) {
+        let mut table = TableBuilder { columns: vec![] };
+        f(&mut table);
+
+        self.push(Operation::CreateTable { name: name.to_string(), columns: table.columns })
+    }
+
+    /// Drops a table. Can't be reversed; use `drop_table_def` for that.
+    pub fn drop_table(&mut self, name: &str) -> &mut Schema {
+        self.push(Operation::DropTable { name: name.to_string(), columns: None })
+    }
+
+    /// Drops a table, keeping the column definitions given to `f` so that
+    /// it can be recreated.
+    pub fn drop_table_def<F>(&mut self, name: &str, f: F) -> &mut Schema where F: FnOnce(&mut TableBuilder) {
+        let mut table = TableBuilder { columns: vec![] };
+        f(&mut table);
+
+        self.push(Operation::DropTable { name: name.to_string(), columns: Some(table.columns) })
+    }
+
+    pub fn rename_table(&mut self, from: &str, to: &str) -> &mut Schema {
+        self.push(Operation::RenameTable { from: from.to_string(), to: to.to_string() })
+    }
+
+    pub fn add_column(&mut self, table: &str, column: Column) -> &mut Schema {
+        self.push(Operation::AddColumn { table: table.to_string(), column: column })
+    }
+
+    /// Drops a column. Can't be reversed; use `drop_column_def` for that.
+    pub fn drop_column(&mut self, table: &str, name: &str) -> &mut Schema {
+        self.push(Operation::DropColumn { table: table.to_string(), name: name.to_string(), column: None })
+    }
+
+    /// Drops a column, keeping its definition so that it can be restored.
+    pub fn drop_column_def(&mut self, table: &str, column: Column) -> &mut Schema {
+        self.push(Operation::DropColumn { table: table.to_string(), name: column.name.clone(), column: Some(column) })
+    }
+
+    pub fn rename_column(&mut self, table: &str, from: &str, to: &str) -> &mut Schema {
+        self.push(Operation::RenameColumn { table: table.to_string(), from: from.to_string(), to: to.to_string() })
+    }
+
+    /// Changes a column type. Can't be reversed; use `change_column_type_from` for that.
+    pub fn change_column_type(&mut self, table: &str, column: &str, to: Type) -> &mut Schema {
+        self.push(Operation::ChangeColumnType { table: table.to_string(), column: column.to_string(), to: to, from: None })
+    }
+
+    pub fn change_column_type_from(&mut self, table: &str, column: &str, from: Type, to: Type) -> &mut Schema {
+        self.push(Operation::ChangeColumnType { table: table.to_string(), column: column.to_string(), to: to, from: Some(from) })
+    }
+
+    /// Adds an index named `<table>_<columns>_idx`.
+    pub fn add_index(&mut self, table: &str, columns: &[&str]) -> &mut Schema {
+        self.push(Operation::AddIndex(Index {
+            name: format!("{}_{}_idx", table, columns.connect("_")),
+            table: table.to_string(),
+            columns: columns.iter().map(|c| c.to_string()).collect(),
+            unique: false
+        }))
+    }
+
+    /// Adds a unique index named `<table>_<columns>_key`.
+    pub fn add_unique_index(&mut self, table: &str, columns: &[&str]) -> &mut Schema {
+        self.push(Operation::AddIndex(Index {
+            name: format!("{}_{}_key", table, columns.connect("_")),
+            table: table.to_string(),
+            columns: columns.iter().map(|c| c.to_string()).collect(),
+            unique: true
+        }))
+    }
+
+    pub fn drop_index(&mut self, name: &str) -> &mut Schema {
+        self.push(Operation::DropIndex { name: name.to_string(), index: None })
+    }
+
+    /// Adds a foreign key named `<table>_<column>_fkey`.
+    pub fn add_foreign_key(&mut self, table: &str, column: &str, ref_table: &str, ref_column: &str) -> &mut Schema {
+        self.push(Operation::AddForeignKey(ForeignKey {
+            name: format!("{}_{}_fkey", table, column),
+            table: table.to_string(),
+            column: column.to_string(),
+            ref_table: ref_table.to_string(),
+            ref_column: ref_column.to_string(),
+            on_delete: None
+        }))
+    }
+
+    /// Like `add_foreign_key`, with an `ON DELETE` action such as `CASCADE`.
+    pub fn add_foreign_key_on_delete(&mut self, table: &str, column: &str, ref_table: &str, ref_column: &str,
+                                     on_delete: &str) -> &mut Schema {
+        self.push(Operation::AddForeignKey(ForeignKey {
+            name: format!("{}_{}_fkey", table, column),
+            table: table.to_string(),
+            column: column.to_string(),
+            ref_table: ref_table.to_string(),
+            ref_column: ref_column.to_string(),
+            on_delete: Some(on_delete.to_string())
+        }))
+    }
+
+    pub fn drop_foreign_key(&mut self, table: &str, name: &str) -> &mut Schema {
+        self.push(Operation::DropForeignKey { table: table.to_string(), name: name.to_string(), foreign_key: None })
+    }
+
+    /// Raw SQL. Can't be reversed.
+    pub fn execute(&mut self, sql: &str) -> &mut Schema {
+        self.push(Operation::Sql(sql.to_string()))
+    }
+
+    pub fn operations(&self) -> &[Operation] {
+        &self.operations
+    }
+
+    pub fn to_sql(&self) -> String {
+        let statements: Vec<String> = self.operations.iter().map(|op| op.to_sql()).collect();
+        statements.connect("\n")
+    }
+
+    /// Reverses every operation, last to first. Returns the first operation
+    /// that can't be reversed as the error.
+    pub fn reverse(&self) -> Result<Schema, Operation> {
+        let mut reversed = Schema::new();
+
+        for operation in self.operations.iter().rev() {
+            match operation.reverse() {
+                Some(reverse) => { reversed.push(reverse); },
+                None => return Err(operation.clone())
+            }
+        }
+
+        Ok(reversed)
+    }
+
+    pub fn run<Conn: MigrationConnection>(&self, cn: &Conn) -> Result<(), Conn::Error> {
+        cn.batch_execute(&self.to_sql())
+    }
+}
+
+/// Migration written as a single list of schema operations. `up` runs them
+/// in order and `down` runs their reverses from last to first, so the two
+/// can't drift apart. Migrations containing an operation that can't be
+/// reversed (raw SQL, a drop without the dropped definition) are refused by
+/// the runner when it has to revert them.
+///
+/// ```ignore
+/// pub struct AddPadawans;
+///
+/// impl ChangeMigration for AddPadawans {
+///     fn change(&self, schema: &mut Schema) {
+///         schema.add_column("jedi", Column::new("padawan_id", Type::Integer));
+///         schema.add_foreign_key("jedi", "padawan_id", "padawan", "id");
+///     }
+/// }
+/// ```
+pub trait ChangeMigration {
+    fn change(&self, schema: &mut Schema);
+
+    /// See `RawMigration::transactional`.
+    fn transactional(&self) -> bool { true }
+
+    fn schema(&self) -> Schema {
+        let mut schema = Schema::new();
+        self.change(&mut schema);
+        schema
+    }
+}
+
+fn irreversible_error(operation: &Operation) -> String {
+    format!("`{}` can't be reversed", operation)
+}
+
+/// `down` panics when the migration can't be reversed. The runner checks
+/// `irreversible` first and refuses with `MigrationError::Irreversible`.
+impl<Conn: MigrationConnection, T: ChangeMigration> RawMigration<Conn> for T {
+    fn up(&self, cn: &Conn) -> Result<(), Conn::Error> {
+        self.schema().run(cn)
+    }
+
+    fn down(&self, cn: &Conn) -> Result<(), Conn::Error> {
+        match self.schema().reverse() {
+            Ok(reversed) => reversed.run(cn),
+            Err(operation) => panic!("{}", irreversible_error(&operation))
+        }
+    }
+
+    fn transactional(&self) -> bool {
+        ChangeMigration::transactional(self)
+    }
+
+    fn irreversible(&self) -> Option<String> {
+        self.schema().reverse().err().map(|operation| irreversible_error(&operation))
+    }
+
+    fn sql(&self, direction: Direction) -> Option<String> {
+        match direction {
+            Direction::Up => Some(self.schema().to_sql()),
+            Direction::Down => self.schema().reverse().ok().map(|reversed| reversed.to_sql())
+        }
+    }
+}
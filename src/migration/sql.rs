@@ -0,0 +1,44 @@
+use super::{RawMigration, MigrationConnection, Direction};
+
+/// Migration backed by plain SQL, generated by `load_migrations!` for
+/// `.sql` migration files.
+pub struct SqlMigration {
+    up: &'static str,
+    down: Option<&'static str>
+}
+
+impl SqlMigration {
+    pub fn new(up: &'static str, down: Option<&'static str>) -> SqlMigration {
+        SqlMigration {
+            up: up,
+            down: down
+        }
+    }
+}
+
+impl<Conn: MigrationConnection> RawMigration<Conn> for SqlMigration {
+    fn up(&self, cn: &Conn) -> Result<(), Conn::Error> {
+        cn.batch_execute(self.up)
+    }
+
+    fn down(&self, cn: &Conn) -> Result<(), Conn::Error> {
+        match self.down {
+            Some(down) => cn.batch_execute(down),
+            None => Ok(())
+        }
+    }
+
+    fn irreversible(&self) -> Option<String> {
+        match self.down {
+            Some(_) => None,
+            None => Some("no down SQL provided".to_string())
+        }
+    }
+
+    fn sql(&self, direction: Direction) -> Option<String> {
+        match direction {
+            Direction::Up => Some(self.up.to_string()),
+            Direction::Down => self.down.map(|down| down.to_string())
+        }
+    }
+}
@@ -18,11 +18,18 @@ extern crate r2d2_postgres;
 extern crate test;
 
 use std::env;
+use std::rc::Rc;
+use std::cell::RefCell;
 use deuterium::*;
 use deuterium_orm::*;
 use time::Timespec;
 
 use postgres::Connection;
+use deuterium_orm::migration::{RawMigration, Direction, Config};
+use deuterium_orm::migration::{Migration, Migrations, SqlMigration, SilentReporter};
+use deuterium_orm::migration::{Reporter, RecordingConnection, MigrationError};
+use deuterium_orm::migration::{dump, cli, OutOfOrderPolicy};
+use deuterium_orm::migration::schema::{Schema, Column, Type, Operation, ChangeMigration};
 
 macro_rules! assert_sql {
     ($query:expr, $s:expr) => (
@@ -92,12 +99,16 @@ fn setup_tables(cn: &Connection) {
     "#).unwrap();
 }
 
-fn setup_pg() -> adapter::postgres::PostgresPool {
-
-    let connection_uri = match env::var("POSTGRES_CONNECTION") {
+fn connection_uri() -> String {
+    match env::var("POSTGRES_CONNECTION") {
         Ok(val) => val,
         Err(_) => "postgres://localhost/jedi".to_string()
-    };
+    }
+}
+
+fn setup_pg() -> adapter::postgres::PostgresPool {
+
+    let connection_uri = connection_uri();
 
     let manager = r2d2_postgres::PostgresConnectionManager::new(&connection_uri[..], ::postgres::SslMode::None).unwrap();
     let config = r2d2::Config::builder()
@@ -182,3 +193,794 @@ fn delete() {
 
     assert_eq!(exec_pg!(&darth.delete_query(), &*cn, &[]), 1);
 }
+
+#[test]
+fn try_macros() {
+    let pool = setup_pg();
+    let cn = pool.get().unwrap();
+    setup_tables(&*cn);
+
+    let luke = try_query_model!(
+        &Jedi::table().select_all().where_(Jedi::name_f().is("Luke Skywalker".to_string())).first(),
+        &*cn, &[]
+    ).unwrap().unwrap();
+    assert_eq!(luke.get_force_level(), &100);
+
+    let mut jedi = Jedi::empty();
+    jedi.set_name("Qui-Gon Jinn, whose name is far too long for the column".to_string());
+    jedi.set_force_level(95);
+    jedi.set_side(Side::LightSide);
+
+    match try_exec!(&jedi.create_query(), &*cn, &[]).unwrap_err() {
+        deuterium_orm::Error::Sql(err) => {
+            assert!(err.sql.unwrap().starts_with("INSERT INTO jedi"));
+            assert_eq!(err.location.unwrap().0, file!());
+        },
+        err => panic!("unexpected error {}", err)
+    }
+
+    let mut duplicate = Jedi::empty();
+    duplicate.set_id(1);
+    duplicate.set_name("Luke Skywalker".to_string());
+    duplicate.set_force_level(100);
+    duplicate.set_side(Side::LightSide);
+
+    let err = try_exec!(&duplicate.create_query(), &*cn, &[]).unwrap_err();
+    assert_eq!(err.sql_kind(), Some(&SqlErrorKind::UniqueViolation { constraint: Some("jedi_pkey".to_string()) }));
+
+    let mut unsaved = Jedi::empty();
+    assert!(unsaved.try_get_name().is_err());
+    assert!(unsaved.try_delete_query().is_err());
+}
+
+#[test]
+fn transactions() {
+    use deuterium_orm::adapter::postgres::{transaction, transaction_with, TransactionOptions, IsolationLevel};
+
+    let pool = setup_pg();
+    let cn = pool.get().unwrap();
+    setup_tables(&*cn);
+
+    let maul = || Jedi::table().select_all().where_(Jedi::name_f().is("Darth Maul".to_string())).first();
+
+    let result: Result<(), deuterium_orm::Error> = transaction(&*cn, |tx| {
+        let mut darth = try!(try_query_model!(&maul(), tx, &[])).unwrap();
+        try!(try_exec!(&darth.delete_query(), tx, &[]));
+        Err(deuterium_orm::Error::MissingPrimaryKey { table: "jedi" })
+    });
+    assert!(result.is_err());
+    assert!(query_model!(&maul(), &*cn, &[]).is_some());
+
+    let options = TransactionOptions { isolation_level: Some(IsolationLevel::Serializable), read_only: false };
+    transaction_with(&*cn, &options, |tx| {
+        // The inner savepoint is rolled back, the outer transaction still commits.
+        let inner: Result<(), deuterium_orm::Error> = transaction(tx, |tx| {
+            let mut darth = try!(try_query_model!(&maul(), tx, &[])).unwrap();
+            try!(try_exec!(&darth.delete_query(), tx, &[]));
+            Err(deuterium_orm::Error::MissingPrimaryKey { table: "jedi" })
+        });
+        assert!(inner.is_err());
+
+        let mut anakin = query_model!(
+            &Jedi::table().select_all().where_(Jedi::name_f().is("Anakin Skywalker".to_string())).first(),
+            tx, &[]
+        ).unwrap();
+        anakin.set_side(Side::LightSide);
+        try_exec!(&anakin.update_query(), tx, &[]).map(|_| ())
+    }).unwrap();
+
+    assert!(query_model!(&maul(), &*cn, &[]).is_some());
+    let anakin = query_model!(
+        &Jedi::table().select_all().where_(Jedi::name_f().is("Anakin Skywalker".to_string())).first(),
+        &*cn, &[]
+    ).unwrap();
+    assert_eq!(anakin.get_side(), &Side::LightSide);
+}
+
+#[test]
+fn retry_transactions() {
+    use std::cell::Cell;
+    use deuterium_orm::adapter::postgres::{retry_transaction, RetryPolicy};
+
+    const SERIALIZATION_FAILURE: &'static str =
+        "DO $$ BEGIN RAISE EXCEPTION USING ERRCODE = 'serialization_failure'; END $$;";
+
+    let pool = setup_pg();
+    let cn = pool.get().unwrap();
+    setup_tables(&*cn);
+
+    let retries = Rc::new(RefCell::new(vec![]));
+    let mut policy = RetryPolicy::default();
+    policy.backoff_ms = 1;
+    let observed = retries.clone();
+    policy.observer = Some(Box::new(move |attempt: u32, _: u32, _: &deuterium_orm::Error| {
+        observed.borrow_mut().push(attempt);
+    }));
+
+    // Only serialization failures and deadlocks are retried.
+    let attempts = Cell::new(0);
+    let result: Result<(), deuterium_orm::Error> = retry_transaction(&*cn, &policy, |_| {
+        attempts.set(attempts.get() + 1);
+        Err(deuterium_orm::Error::MissingPrimaryKey { table: "jedi" })
+    });
+    assert!(result.is_err());
+    assert_eq!(attempts.get(), 1);
+    assert!(retries.borrow().is_empty());
+
+    // The first attempt fails with 40001, the second one succeeds.
+    let attempts = Cell::new(0);
+    let jedi = retry_transaction(&*cn, &policy, |tx| {
+        attempts.set(attempts.get() + 1);
+        if attempts.get() == 1 {
+            try!(tx.batch_execute(SERIALIZATION_FAILURE));
+        }
+        try_query_models!(&Jedi::ordered().where_(Jedi::side_f().is(Side::LightSide)), tx, &[])
+    }).unwrap();
+    assert_eq!(jedi.len(), 4);
+    assert_eq!(attempts.get(), 2);
+    assert_eq!(*retries.borrow(), vec![1]);
+
+    // Gives up after `max_retries` retries.
+    retries.borrow_mut().clear();
+    policy.max_retries = 2;
+    let attempts = Cell::new(0);
+    let result: Result<(), deuterium_orm::Error> = retry_transaction(&*cn, &policy, |tx| {
+        attempts.set(attempts.get() + 1);
+        try!(tx.batch_execute(SERIALIZATION_FAILURE));
+        Ok(())
+    });
+    assert_eq!(attempts.get(), 3);
+    assert_eq!(*retries.borrow(), vec![1, 2]);
+    assert_eq!(result.unwrap_err().sql_kind(), Some(&deuterium_orm::SqlErrorKind::SerializationFailure));
+}
+
+#[test]
+fn schema_dsl() {
+    let mut schema = Schema::new();
+    schema.create_table("padawan", |t| {
+        t.id();
+        t.column(Column::new("name", Type::Varchar(40)).not_null());
+        t.column(Column::new("master_id", Type::Integer).not_null());
+    });
+    schema.add_foreign_key("padawan", "master_id", "jedi", "id");
+    schema.add_index("padawan", &["name"]);
+
+    assert_eq!(&schema.to_sql(), r#"CREATE TABLE "padawan" (
+    "id" serial PRIMARY KEY,
+    "name" varchar(40) NOT NULL,
+    "master_id" integer NOT NULL
+);
+ALTER TABLE "padawan" ADD CONSTRAINT "padawan_master_id_fkey" FOREIGN KEY ("master_id") REFERENCES "jedi" ("id");
+CREATE INDEX "padawan_name_idx" ON "padawan" ("name");"#);
+
+    assert_eq!(&schema.reverse().unwrap().to_sql(), r#"DROP INDEX "padawan_name_idx";
+ALTER TABLE "padawan" DROP CONSTRAINT "padawan_master_id_fkey";
+DROP TABLE "padawan";"#);
+
+    schema.drop_column("jedi", "side");
+    assert_eq!(schema.reverse().unwrap_err(), Operation::DropColumn {
+        table: "jedi".to_string(),
+        name: "side".to_string(),
+        column: None
+    });
+}
+
+#[test]
+fn schema_dsl_quotes_and_reverses_drops() {
+    let mut schema = Schema::new();
+    schema.drop_table_def("order", |t| {
+        t.id();
+        t.column(Column::new("userId", Type::Integer));
+    });
+    schema.add_foreign_key_on_delete("items", "order_id", "order", "id", "CASCADE");
+
+    assert_eq!(&schema.to_sql(), r#"DROP TABLE "order";
+ALTER TABLE "items" ADD CONSTRAINT "items_order_id_fkey" FOREIGN KEY ("order_id") REFERENCES "order" ("id") ON DELETE CASCADE;"#);
+
+    assert_eq!(&schema.reverse().unwrap().to_sql(), r#"ALTER TABLE "items" DROP CONSTRAINT "items_order_id_fkey";
+CREATE TABLE "order" (
+    "id" serial PRIMARY KEY,
+    "userId" integer
+);"#);
+}
+
+struct RetireSides;
+
+impl ChangeMigration for RetireSides {
+    fn change(&self, schema: &mut Schema) {
+        schema.add_column("jedi", Column::new("retired", Type::Boolean).not_null().default("false"));
+        schema.drop_column("jedi", "side");
+    }
+}
+
+#[test]
+fn change_migration() {
+    let migration = RetireSides;
+
+    assert_eq!(RawMigration::<Connection>::sql(&migration, Direction::Up).unwrap(),
+        r#"ALTER TABLE "jedi" ADD COLUMN "retired" boolean NOT NULL DEFAULT false;
+ALTER TABLE "jedi" DROP COLUMN "side";"#.to_string());
+
+    assert_eq!(RawMigration::<Connection>::irreversible(&migration).unwrap(),
+        r#"`ALTER TABLE "jedi" DROP COLUMN "side";` can't be reversed"#.to_string());
+}
+
+#[test]
+fn migrations_table_name() {
+    let mut config = Config::default();
+    assert_eq!(config.table_name(), "\"schema_migrations\"".to_string());
+
+    config.schema = Some("billing".to_string());
+    assert_eq!(config.table_name(), "\"billing\".\"schema_migrations\"".to_string());
+}
+
+#[test]
+fn schema_dump_round_trip() {
+    let pool = setup_pg();
+    let cn = pool.get().unwrap();
+
+    cn.batch_execute(r#"
+        DROP SCHEMA IF EXISTS dump_source CASCADE;
+        DROP SCHEMA IF EXISTS dump_target CASCADE;
+        CREATE SCHEMA dump_source;
+        CREATE SCHEMA dump_target;
+        SET search_path TO dump_source;
+
+        CREATE TYPE status AS ENUM ('open', 'shipped');
+        CREATE DOMAIN quantity AS integer NOT NULL CHECK (VALUE > 0);
+        CREATE TYPE line AS (sku text, amount quantity);
+        CREATE SEQUENCE invoice_numbers START WITH 1000 INCREMENT BY 10 MAXVALUE 99990 CYCLE;
+
+        CREATE TABLE orders (
+            id       serial PRIMARY KEY,
+            "order"  integer NOT NULL,
+            "userId" integer CHECK ("userId" > 0),
+            status   status NOT NULL DEFAULT 'open',
+            lines    line[]
+        );
+        CREATE TABLE items (
+            id       integer GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+            order_id integer REFERENCES orders (id)
+        );
+        CREATE INDEX items_order_id ON items (order_id);
+    "#).unwrap();
+
+    let config = Config::default();
+    deuterium_orm::migration::ensure_schema_migrations(&*cn, &config).unwrap();
+    deuterium_orm::migration::insert_version(&1, "create_orders", Some("abc"), &0, &*cn, &config).unwrap();
+
+    let source = dump::dump_schema(&*cn, &config).unwrap();
+    assert!(source.contains("\"userId\" integer"));
+    assert!(source.contains("CREATE INDEX items_order_id ON items USING btree (order_id);"));
+    assert!(source.contains("ALTER SEQUENCE orders_id_seq OWNED BY orders.id;"));
+    assert!(source.contains("CREATE TYPE status AS ENUM ('open', 'shipped');"));
+    assert!(source.contains("CREATE DOMAIN quantity AS integer NOT NULL CONSTRAINT quantity_check CHECK ((VALUE > 0));"));
+    assert!(source.contains("CREATE TYPE line AS (sku text, amount quantity);"));
+    assert!(source.contains("CREATE SEQUENCE invoice_numbers AS bigint START WITH 1000 INCREMENT BY 10 MINVALUE 1 MAXVALUE 99990 CACHE 1 CYCLE;"));
+    assert!(!source.contains("_not_null"));
+
+    cn.batch_execute("SET search_path TO dump_target;").unwrap();
+    dump::load_schema(&*cn, &source).unwrap();
+    let target = dump::dump_schema(&*cn, &config).unwrap();
+
+    cn.batch_execute("SET search_path TO DEFAULT;").unwrap();
+    assert_eq!(source, target);
+}
+
+fn sql_migration(version: u64, name: &str, up: &'static str, down: &'static str) -> Box<Migration<Connection>> {
+    Box::new(Migration::new(version, name, Box::new(SqlMigration::new(up, Some(down)))))
+}
+
+/// Runner config keeping each test in its own schema.
+fn migration_config(schema: &str) -> Config {
+    let mut config = Config::default();
+    config.schema = Some(schema.to_string());
+    config.search_path = Some(schema.to_string());
+    config.reporter = Box::new(SilentReporter);
+    config
+}
+
+fn reset_schema(cn: &Connection, schema: &str) {
+    cn.batch_execute(&format!("DROP SCHEMA IF EXISTS {0} CASCADE; CREATE SCHEMA {0};", schema)).unwrap();
+}
+
+fn table_exists(cn: &Connection, schema: &str, table: &str) -> bool {
+    let stmt = cn.prepare("SELECT 1 FROM information_schema.tables WHERE table_schema = $1 AND table_name = $2;").unwrap();
+    let rows = stmt.query(&[&schema, &table]).unwrap();
+    rows.iter().next().is_some()
+}
+
+/// Versions recorded in the schema's migrations table, newest first.
+fn applied_versions(cn: &Connection, schema: &str) -> Vec<i64> {
+    let stmt = cn.prepare(&format!("SELECT version FROM {}.schema_migrations ORDER BY version DESC;", schema)).unwrap();
+    let rows = stmt.query(&[]).unwrap();
+    rows.iter().map(|row| row.get(0)).collect()
+}
+
+fn runner_migrations() -> Migrations<Connection> {
+    vec![
+        sql_migration(1, "create_a", "CREATE TABLE a (id integer);", "DROP TABLE a;"),
+        sql_migration(2, "create_b", "CREATE TABLE b (id integer);", "DROP TABLE b;"),
+        sql_migration(3, "create_c", "CREATE TABLE c (id integer);", "DROP TABLE c;")
+    ]
+}
+
+fn baseline_migrations() -> Migrations<Connection> {
+    vec![
+        sql_migration(1, "create_a", "CREATE TABLE a (id integer);", "DROP TABLE a;"),
+        sql_migration(2, "create_b", "CREATE TABLE b (id integer);", "DROP TABLE b;"),
+        Box::new(Migration::new(3, "baseline", Box::new(SqlMigration::new(
+            "CREATE TABLE a (id integer); CREATE TABLE b (id integer);",
+            Some("DROP TABLE b; DROP TABLE a;")
+        ))).baseline(true))
+    ]
+}
+
+#[test]
+fn baseline_on_fresh_database() {
+    let pool = setup_pg();
+    let cn = pool.get().unwrap();
+    let config = migration_config("baseline_fresh");
+    reset_schema(&*cn, "baseline_fresh");
+
+    let migrations = baseline_migrations();
+    let report = migration::run(&migrations, &*cn, &config).unwrap();
+    assert_eq!(report.applied, vec![3]);
+
+    let status = migration::status(&migrations, &*cn, &config).unwrap();
+    assert!(status.pending().is_empty());
+    assert!(status.is_up_to_date());
+}
+
+#[test]
+fn baseline_on_existing_database() {
+    let pool = setup_pg();
+    let cn = pool.get().unwrap();
+    let config = migration_config("baseline_existing");
+    reset_schema(&*cn, "baseline_existing");
+
+    let mut migrations = baseline_migrations();
+    let old: Migrations<Connection> = vec![migrations.remove(0)];
+    migration::run(&old, &*cn, &config).unwrap();
+
+    // v2 is missing from the database and has to run before v3 is recorded.
+    let migrations = baseline_migrations();
+    let report = migration::run(&migrations, &*cn, &config).unwrap();
+    assert_eq!(report.applied, vec![2]);
+    assert_eq!(report.marked_applied, vec![3]);
+    assert!(table_exists(&*cn, "baseline_existing", "b"));
+    assert!(migration::status(&migrations, &*cn, &config).unwrap().is_up_to_date());
+}
+
+#[test]
+fn baseline_catch_up_follows_out_of_order_policy() {
+    let pool = setup_pg();
+    let cn = pool.get().unwrap();
+    let mut config = migration_config("baseline_out_of_order");
+    reset_schema(&*cn, "baseline_out_of_order");
+
+    let mut old = runner_migrations();
+    old.remove(1);
+    migration::run(&old, &*cn, &config).unwrap();
+
+    // v2 predates the baseline and the applied v3.
+    let mut migrations = runner_migrations();
+    migrations.push(Box::new(Migration::new(4, "baseline", Box::new(SqlMigration::new(
+        "CREATE TABLE a (id integer); CREATE TABLE b (id integer); CREATE TABLE c (id integer);", None
+    ))).baseline(true)));
+
+    config.out_of_order_policy = OutOfOrderPolicy::Refuse;
+    match migration::run(&migrations, &*cn, &config) {
+        Err(MigrationError::OutOfOrder(versions)) => assert_eq!(versions, vec![2]),
+        _ => panic!("expected v2 to be refused")
+    }
+    assert_eq!(applied_versions(&*cn, "baseline_out_of_order"), vec![3, 1]);
+
+    config.out_of_order_policy = OutOfOrderPolicy::Allow;
+    let report = migration::run(&migrations, &*cn, &config).unwrap();
+    assert_eq!(report.applied, vec![2]);
+    assert_eq!(report.marked_applied, vec![4]);
+    assert_eq!(report.out_of_order, vec![2]);
+}
+
+#[test]
+fn newer_baseline_squashes_older_one() {
+    let pool = setup_pg();
+    let cn = pool.get().unwrap();
+    let config = migration_config("baseline_squash");
+    reset_schema(&*cn, "baseline_squash");
+
+    let old: Migrations<Connection> = vec![sql_migration(1, "create_a", "CREATE TABLE a (id integer);", "DROP TABLE a;")];
+    migration::run(&old, &*cn, &config).unwrap();
+
+    let mut migrations = baseline_migrations();
+    migrations.push(sql_migration(4, "create_c", "CREATE TABLE c (id integer);", "DROP TABLE c;"));
+    migrations.push(Box::new(Migration::new(5, "baseline", Box::new(SqlMigration::new(
+        "CREATE TABLE a (id integer); CREATE TABLE b (id integer); CREATE TABLE c (id integer);", None
+    ))).baseline(true)));
+
+    // The missing v2 and v4 run; both baselines are only recorded.
+    let report = migration::run(&migrations, &*cn, &config).unwrap();
+    assert_eq!(report.applied, vec![2, 4]);
+    assert_eq!(report.marked_applied, vec![3, 5]);
+    assert!(table_exists(&*cn, "baseline_squash", "c"));
+}
+
+struct CreateLog;
+
+impl RawMigration<RecordingConnection> for CreateLog {
+    fn up(&self, cn: &RecordingConnection) -> postgres::Result<()> {
+        try!(cn.execute("CREATE TABLE log (id integer)", &[]));
+        cn.batch_execute("CREATE INDEX log_id ON log (id);")
+    }
+
+    fn down(&self, cn: &RecordingConnection) -> postgres::Result<()> {
+        cn.batch_execute("DROP TABLE log;")
+    }
+}
+
+struct PreviewReporter(Rc<RefCell<Vec<Option<String>>>>);
+
+impl Reporter for PreviewReporter {
+    fn preview(&self, _version: u64, _name: &str, _direction: Direction, sql: Option<&str>, _executed: bool) {
+        self.0.borrow_mut().push(sql.map(|sql| sql.to_string()));
+    }
+}
+
+#[test]
+fn dry_run_prints_executed_sql() {
+    let cn = RecordingConnection::new(Connection::connect(&connection_uri()[..], &postgres::SslMode::None).unwrap());
+    let previews = Rc::new(RefCell::new(vec![]));
+
+    let mut config = Config::default();
+    config.schema = Some("dry_run".to_string());
+    config.search_path = Some("dry_run".to_string());
+    config.dry_run = true;
+    config.reporter = Box::new(PreviewReporter(previews.clone()));
+    cn.batch_execute("DROP SCHEMA IF EXISTS dry_run CASCADE; CREATE SCHEMA dry_run;").unwrap();
+
+    let migrations: Migrations<RecordingConnection> = vec![Box::new(Migration::new(1, "create_log", Box::new(CreateLog)))];
+    let report = migration::run(&migrations, &cn, &config).unwrap();
+    assert_eq!(report.applied, vec![1]);
+    assert_eq!(*previews.borrow(), vec![
+        Some("-- Recorded from execute/batch_execute, other statements are not shown\n\
+              CREATE TABLE log (id integer);\nCREATE INDEX log_id ON log (id);".to_string())
+    ]);
+    assert!(!table_exists(&cn, "dry_run", "log"));
+}
+
+#[test]
+fn rollback_reverts_newest_first() {
+    let pool = setup_pg();
+    let cn = pool.get().unwrap();
+    let config = migration_config("runner_rollback");
+    reset_schema(&*cn, "runner_rollback");
+
+    let migrations = runner_migrations();
+    migration::run(&migrations, &*cn, &config).unwrap();
+
+    let report = migration::rollback(2, &migrations, &*cn, &config).unwrap();
+    assert_eq!(report.reverted, vec![3, 2]);
+    assert_eq!(applied_versions(&*cn, "runner_rollback"), vec![1]);
+    assert!(!table_exists(&*cn, "runner_rollback", "c"));
+
+    // More steps than applied migrations reverts what there is.
+    let report = migration::rollback(10, &migrations, &*cn, &config).unwrap();
+    assert_eq!(report.reverted, vec![1]);
+    assert!(applied_versions(&*cn, "runner_rollback").is_empty());
+    assert!(!table_exists(&*cn, "runner_rollback", "a"));
+}
+
+struct WarningReporter(Rc<RefCell<Vec<String>>>);
+
+impl Reporter for WarningReporter {
+    fn warning(&self, message: &str) {
+        self.0.borrow_mut().push(message.to_string());
+    }
+}
+
+#[test]
+fn out_of_order_policy() {
+    let pool = setup_pg();
+    let cn = pool.get().unwrap();
+    let mut config = migration_config("runner_out_of_order");
+    reset_schema(&*cn, "runner_out_of_order");
+
+    let mut old = runner_migrations();
+    old.remove(1);
+    migration::run(&old, &*cn, &config).unwrap();
+    let migrations = runner_migrations();
+
+    config.out_of_order_policy = OutOfOrderPolicy::Refuse;
+    match migration::run(&migrations, &*cn, &config) {
+        Err(MigrationError::OutOfOrder(versions)) => assert_eq!(versions, vec![2]),
+        _ => panic!("expected v2 to be refused")
+    }
+    assert_eq!(applied_versions(&*cn, "runner_out_of_order"), vec![3, 1]);
+
+    let warnings = Rc::new(RefCell::new(vec![]));
+    config.out_of_order_policy = OutOfOrderPolicy::Warn;
+    config.reporter = Box::new(WarningReporter(warnings.clone()));
+    let report = migration::run(&migrations, &*cn, &config).unwrap();
+    assert_eq!(report.applied, vec![2]);
+    assert_eq!(report.out_of_order, vec![2]);
+    assert_eq!(*warnings.borrow(), vec!["applying migrations out of order: [2]".to_string()]);
+
+    reset_schema(&*cn, "runner_out_of_order");
+    config.out_of_order_policy = OutOfOrderPolicy::Allow;
+    config.reporter = Box::new(SilentReporter);
+    migration::run(&old, &*cn, &config).unwrap();
+    let report = migration::run(&migrations, &*cn, &config).unwrap();
+    assert_eq!(report.applied, vec![2]);
+    assert_eq!(report.out_of_order, vec![2]);
+    assert_eq!(applied_versions(&*cn, "runner_out_of_order"), vec![3, 2, 1]);
+}
+
+#[test]
+fn rollback_refuses_orphaned_versions() {
+    let pool = setup_pg();
+    let cn = pool.get().unwrap();
+    let config = migration_config("runner_orphaned");
+    reset_schema(&*cn, "runner_orphaned");
+
+    let mut migrations = runner_migrations();
+    migration::run(&migrations, &*cn, &config).unwrap();
+    migrations.pop();
+
+    match migration::rollback(1, &migrations, &*cn, &config) {
+        Err(MigrationError::Orphaned(versions)) => assert_eq!(versions, vec![3]),
+        _ => panic!("expected orphaned version 3")
+    }
+    assert_eq!(applied_versions(&*cn, "runner_orphaned"), vec![3, 2, 1]);
+
+    let status = migration::status(&migrations, &*cn, &config).unwrap();
+    assert_eq!(status.orphaned, vec![3]);
+    assert!(status.migrations.iter().all(|m| m.applied));
+    assert!(!status.is_up_to_date());
+}
+
+#[test]
+fn failed_migration_is_rolled_back() {
+    let pool = setup_pg();
+    let cn = pool.get().unwrap();
+    let config = migration_config("runner_failure");
+    reset_schema(&*cn, "runner_failure");
+
+    let migrations: Migrations<Connection> = vec![
+        sql_migration(1, "create_a", "CREATE TABLE a (id integer);", "DROP TABLE a;"),
+        sql_migration(2, "broken", "CREATE TABLE b (id integer); SELECT 1 / 0;", "DROP TABLE b;")
+    ];
+    assert!(migration::run(&migrations, &*cn, &config).is_err());
+
+    assert_eq!(applied_versions(&*cn, "runner_failure"), vec![1]);
+    assert!(table_exists(&*cn, "runner_failure", "a"));
+    assert!(!table_exists(&*cn, "runner_failure", "b"));
+
+    let status = migration::status(&migrations, &*cn, &config).unwrap();
+    assert_eq!(status.pending().iter().map(|m| m.version).collect::<Vec<u64>>(), vec![2]);
+    assert!(status.orphaned.is_empty());
+}
+
+#[test]
+fn migrate_to_and_redo() {
+    let pool = setup_pg();
+    let cn = pool.get().unwrap();
+    let config = migration_config("runner_migrate_to");
+    reset_schema(&*cn, "runner_migrate_to");
+
+    let migrations = runner_migrations();
+    let report = migration::migrate_to(2, &migrations, &*cn, &config).unwrap();
+    assert_eq!(report.applied, vec![1, 2]);
+    assert_eq!(applied_versions(&*cn, "runner_migrate_to"), vec![2, 1]);
+
+    migration::run(&migrations, &*cn, &config).unwrap();
+    let report = migration::migrate_to(1, &migrations, &*cn, &config).unwrap();
+    assert_eq!(report.reverted, vec![3, 2]);
+    assert_eq!(applied_versions(&*cn, "runner_migrate_to"), vec![1]);
+
+    let report = migration::redo(&migrations, &*cn, &config).unwrap();
+    assert_eq!(report.reverted, vec![1]);
+    assert_eq!(report.applied, vec![1]);
+    assert_eq!(applied_versions(&*cn, "runner_migrate_to"), vec![1]);
+    assert!(table_exists(&*cn, "runner_migrate_to", "a"));
+}
+
+fn parse_args(args: &[&str]) -> Result<cli::Options, String> {
+    let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+    cli::parse_args(&args)
+}
+
+fn parse_error(args: &[&str]) -> String {
+    match parse_args(args) {
+        Ok(_) => panic!("expected {:?} to be rejected", args),
+        Err(err) => err
+    }
+}
+
+#[test]
+fn cli_down_steps() {
+    match parse_args(&["down"]).unwrap().command {
+        cli::Command::Down(steps) => assert_eq!(steps, 1),
+        _ => panic!("expected down")
+    }
+    match parse_args(&["down", "3"]).unwrap().command {
+        cli::Command::Down(steps) => assert_eq!(steps, 3),
+        _ => panic!("expected down")
+    }
+    assert_eq!(parse_error(&["down", "three"]), "invalid step count `three`");
+    assert_eq!(parse_error(&["down", "-1"]), "invalid step count `-1`");
+}
+
+#[test]
+fn cli_options() {
+    let options = parse_args(&["up", "--connection", "postgres://localhost/jedi", "--out-of-order", "refuse"]).unwrap();
+    assert_eq!(options.connection, Some("postgres://localhost/jedi".to_string()));
+    assert_eq!(options.config.out_of_order_policy, OutOfOrderPolicy::Refuse);
+    assert_eq!(parse_args(&["up", "--out-of-order", "allow"]).unwrap().config.out_of_order_policy, OutOfOrderPolicy::Allow);
+    assert_eq!(parse_args(&["up", "--out-of-order", "warn"]).unwrap().config.out_of_order_policy, OutOfOrderPolicy::Warn);
+    assert_eq!(parse_error(&["up", "--out-of-order", "sometimes"]), "invalid out-of-order policy `sometimes`");
+
+    assert_eq!(parse_error(&["up", "--connection"]), "--connection requires a value");
+    assert_eq!(parse_error(&["up", "--lock-timeout"]), "--lock-timeout requires a value");
+}
+
+#[test]
+fn cli_usage() {
+    assert!(parse_error(&[]).starts_with("Usage:"));
+    assert!(parse_error(&["sideways"]).starts_with("Usage:"));
+    assert!(parse_error(&["up", "extra"]).starts_with("Usage:"));
+}
+
+/// Keeps the migrations table in memory, standing in for a database the
+/// runner has no built-in support for.
+struct MemoryConnection {
+    statements: RefCell<Vec<String>>,
+    versions: RefCell<Vec<i64>>
+}
+
+impl deuterium_orm::migration::MigrationConnection for MemoryConnection {
+    type Error = std::io::Error;
+
+    fn batch_execute(&self, sql: &str) -> std::io::Result<()> {
+        self.statements.borrow_mut().push(sql.to_string());
+        Ok(())
+    }
+
+    fn ensure_versions_table(&self, _config: &Config) -> std::io::Result<()> { Ok(()) }
+
+    fn query_versions(&self, _config: &Config) -> std::io::Result<Vec<migration::AppliedMigration>> {
+        let mut versions = self.versions.borrow().clone();
+        versions.sort_by(|a, b| b.cmp(a));
+        Ok(versions.into_iter().map(|version| migration::AppliedMigration {
+            version: version,
+            name: None,
+            applied_at: None,
+            duration_ms: None,
+            checksum: None
+        }).collect())
+    }
+
+    fn insert_version(&self, _config: &Config, version: i64, _name: &str, _checksum: Option<&str>,
+                      _duration_ms: i64) -> std::io::Result<()> {
+        self.versions.borrow_mut().push(version);
+        Ok(())
+    }
+
+    fn delete_versions(&self, _config: &Config, version: i64, older: bool) -> std::io::Result<()> {
+        self.versions.borrow_mut().retain(|v| if older { *v > version } else { *v != version });
+        Ok(())
+    }
+
+    fn lock(&self, _config: &Config, _wait: bool) -> std::io::Result<bool> { Ok(true) }
+    fn unlock(&self, _config: &Config) -> std::io::Result<()> { Ok(()) }
+    fn set_search_path(&self, _search_path: &str) -> std::io::Result<String> { Ok(String::new()) }
+    fn dump_schema(&self, _config: &Config) -> std::io::Result<String> { Ok(String::new()) }
+}
+
+#[test]
+fn runner_without_postgres() {
+    let cn = MemoryConnection { statements: RefCell::new(vec![]), versions: RefCell::new(vec![]) };
+    let mut config = Config::default();
+    config.reporter = Box::new(SilentReporter);
+
+    let migrations: Migrations<MemoryConnection> = vec![
+        Box::new(Migration::new(1, "create_a", Box::new(SqlMigration::new("CREATE TABLE a;", Some("DROP TABLE a;"))))),
+        Box::new(Migration::new(2, "create_b", Box::new(SqlMigration::new("CREATE TABLE b;", Some("DROP TABLE b;")))))
+    ];
+
+    let report = migration::run(&migrations, &cn, &config).unwrap();
+    assert_eq!(report.applied, vec![1, 2]);
+
+    let report = migration::rollback(1, &migrations, &cn, &config).unwrap();
+    assert_eq!(report.reverted, vec![2]);
+    assert_eq!(*cn.versions.borrow(), vec![1]);
+    assert_eq!(*cn.statements.borrow(), vec![
+        "BEGIN;", "CREATE TABLE a;", "COMMIT;",
+        "BEGIN;", "CREATE TABLE b;", "COMMIT;",
+        "BEGIN;", "DROP TABLE b;", "COMMIT;"
+    ]);
+}
+
+#[test]
+fn status_is_read_only() {
+    let pool = setup_pg();
+    let cn = pool.get().unwrap();
+    let config = migration_config("status_read_only");
+    reset_schema(&*cn, "status_read_only");
+
+    let migrations = runner_migrations();
+    let status = migration::status(&migrations, &*cn, &config).unwrap();
+    assert_eq!(status.pending().len(), 3);
+    assert!(!table_exists(&*cn, "status_read_only", "schema_migrations"));
+
+    // Tables of older runners only have `version`.
+    cn.batch_execute("CREATE TABLE status_read_only.schema_migrations (version BIGINT NOT NULL);
+        INSERT INTO status_read_only.schema_migrations VALUES (1);").unwrap();
+    let status = migration::status(&migrations, &*cn, &config).unwrap();
+    assert!(status.migrations[0].applied);
+    assert!(status.migrations[0].applied_at.is_none());
+    assert_eq!(status.pending().len(), 2);
+
+    let stmt = cn.prepare("SELECT count(*) FROM information_schema.columns
+        WHERE table_schema = 'status_read_only' AND table_name = 'schema_migrations';").unwrap();
+    let columns: i64 = stmt.query(&[]).unwrap().iter().next().unwrap().get(0);
+    assert_eq!(columns, 1);
+}
+
+struct CommitsOnItsOwn;
+
+impl RawMigration<Connection> for CommitsOnItsOwn {
+    fn up(&self, cn: &Connection) -> postgres::Result<()> {
+        let trans = try!(cn.transaction());
+        try!(trans.batch_execute("CREATE TABLE committed (id integer);"));
+        trans.commit()
+    }
+
+    fn down(&self, cn: &Connection) -> postgres::Result<()> {
+        cn.batch_execute("DROP TABLE committed;")
+    }
+}
+
+#[test]
+fn migration_ending_the_transaction_is_refused() {
+    let cn = Connection::connect(&connection_uri()[..], &postgres::SslMode::None).unwrap();
+    let config = migration_config("nested_transaction");
+    reset_schema(&cn, "nested_transaction");
+
+    let migrations: Migrations = vec![Box::new(Migration::new(1, "commits_on_its_own", Box::new(CommitsOnItsOwn)))];
+    match migration::run(&migrations, &cn, &config) {
+        Err(MigrationError::TransactionEnded { version, .. }) => assert_eq!(version, 1),
+        _ => panic!("expected the migration to be refused")
+    }
+    assert!(applied_versions(&cn, "nested_transaction").is_empty());
+}
+
+struct CreateSeeded;
+
+impl ChangeMigration for CreateSeeded {
+    fn change(&self, schema: &mut Schema) {
+        schema.create_table("seeded", |t| { t.id(); });
+        schema.execute("INSERT INTO seeded DEFAULT VALUES;");
+    }
+}
+
+#[test]
+fn irreversible_change_migration_is_refused() {
+    let pool = setup_pg();
+    let cn = pool.get().unwrap();
+    let config = migration_config("runner_irreversible");
+    reset_schema(&*cn, "runner_irreversible");
+
+    let migrations: Migrations = vec![Box::new(Migration::new(1, "create_seeded", Box::new(CreateSeeded)))];
+    migration::run(&migrations, &*cn, &config).unwrap();
+
+    match migration::rollback(1, &migrations, &*cn, &config) {
+        Err(MigrationError::Irreversible { version, reason, .. }) => {
+            assert_eq!(version, 1);
+            assert_eq!(reason, "`INSERT INTO seeded DEFAULT VALUES;` can't be reversed".to_string());
+        },
+        _ => panic!("expected the rollback to be refused")
+    }
+    assert_eq!(applied_versions(&*cn, "runner_irreversible"), vec![1]);
+    assert!(table_exists(&*cn, "runner_irreversible", "seeded"));
+}